@@ -0,0 +1,467 @@
+//! Command-line tool for inspecting the journals written by `mrogalski_looper::Journal`.
+//!
+//! Events are shown as text when they are valid UTF-8 and as hex otherwise, since the tool doesn't
+//! know the `Codec` which encoded them. Run `looper-cli --help` for the list of commands.
+
+extern crate mrogalski_looper;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mrogalski_looper::{journal_segments, Codec, Record, Replay};
+
+#[cfg(test)]
+mod tests;
+
+const USAGE: &str = "\
+Usage: looper-cli <command> <journal dir> [options]
+
+Commands:
+  segments    List the segments of the journal
+  print       Print the events
+  export      Print the events as JSON lines
+  stats       Count the events and their bytes per kind
+  tail        Print the last events
+  truncate    Delete the oldest segments
+
+Filters for print, export, stats and tail:
+  --kind <kind>        Only events of the given kind
+  --from <seq>         Only events with a sequence number of at least <seq>
+  --to <seq>           Only events with a sequence number of at most <seq>
+  --since <seconds>    Only events handled at or after <seconds> since the Unix epoch
+  --until <seconds>    Only events handled before <seconds> since the Unix epoch
+  --segment <index>    Only events in the given segment
+
+Options:
+  -n <count>           Number of events printed by tail. Defaults to 10
+  --follow             Keep printing new events after tail
+  --keep <count>       Number of the newest segments kept by truncate
+";
+
+// How often `tail --follow` checks the journal for new events.
+const POLL: Duration = Duration::from_millis(500);
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(Usage::Help) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(Usage::Invalid(message)) => {
+            eprintln!("looper-cli: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    let stdout = io::stdout();
+    match args.run(&mut stdout.lock()) {
+        Ok(()) => {}
+        // Output piped to a command such as `head`, which stopped reading.
+        Err(ref error) if error.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(error) => {
+            eprintln!("looper-cli: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Segments,
+    Print,
+    Export,
+    Stats,
+    Tail,
+    Truncate,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Usage {
+    Help,
+    Invalid(String),
+}
+
+/// Parsed command line.
+#[derive(Debug, PartialEq, Eq)]
+struct Args {
+    command: Command,
+    dir: PathBuf,
+    filter: Filter,
+    count: usize,
+    follow: bool,
+    keep: Option<usize>,
+}
+
+/// Conditions which the printed events must meet.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Filter {
+    kind: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    segment: Option<u64>,
+}
+
+impl Filter {
+    fn accepts(&self, segment: u64, record: &Record<Vec<u8>>) -> bool {
+        self.kind.as_ref().is_none_or(|kind| *kind == record.kind)
+            && self.from.is_none_or(|from| record.seq >= from)
+            && self.to.is_none_or(|to| record.seq <= to)
+            && self.since.is_none_or(|since| record.time >= since)
+            && self.until.is_none_or(|until| record.time < until)
+            && self.segment.is_none_or(|index| index == segment)
+    }
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, Usage> {
+        let command = match args.next().as_deref() {
+            Some("segments") => Command::Segments,
+            Some("print") => Command::Print,
+            Some("export") => Command::Export,
+            Some("stats") => Command::Stats,
+            Some("tail") => Command::Tail,
+            Some("truncate") => Command::Truncate,
+            None | Some("help") | Some("-h") | Some("--help") => return Err(Usage::Help),
+            Some(other) => return Err(invalid(format!("unknown command `{}`", other))),
+        };
+        let mut dir = None;
+        let mut parsed = Args {
+            command,
+            dir: PathBuf::new(),
+            filter: Filter::default(),
+            count: 10,
+            follow: false,
+            keep: None,
+        };
+        while let Some(arg) = args.next() {
+            let filter = &mut parsed.filter;
+            match arg.as_str() {
+                "-h" | "--help" => return Err(Usage::Help),
+                "--kind" => filter.kind = Some(value(&arg, args.next())?),
+                "--from" => filter.from = Some(number(&arg, args.next())?),
+                "--to" => filter.to = Some(number(&arg, args.next())?),
+                "--since" => filter.since = Some(seconds(&arg, args.next())?),
+                "--until" => filter.until = Some(seconds(&arg, args.next())?),
+                "--segment" => filter.segment = Some(number(&arg, args.next())?),
+                "-n" => parsed.count = number(&arg, args.next())? as usize,
+                "--follow" | "-f" => parsed.follow = true,
+                "--keep" => parsed.keep = Some(number(&arg, args.next())? as usize),
+                _ if arg.starts_with('-') => {
+                    return Err(invalid(format!("unknown option `{}`", arg)))
+                }
+                _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+                _ => return Err(invalid(format!("unexpected argument `{}`", arg))),
+            }
+        }
+        parsed.dir = dir.ok_or_else(|| invalid("missing journal directory".to_string()))?;
+        match parsed.keep {
+            None if command == Command::Truncate => {
+                Err(invalid("truncate requires --keep".to_string()))
+            }
+            Some(0) => Err(invalid("--keep must be at least 1".to_string())),
+            _ => Ok(parsed),
+        }
+    }
+
+    fn run(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self.command {
+            Command::Segments => self.segments(out),
+            Command::Print => {
+                self.each(|segment, record| writeln!(out, "{}", text(segment, &record)))
+            }
+            Command::Export => {
+                self.each(|segment, record| writeln!(out, "{}", json(segment, &record)))
+            }
+            Command::Stats => self.stats(out),
+            Command::Tail => self.tail(out),
+            Command::Truncate => self.truncate(out),
+        }
+    }
+
+    /// Calls `f` for every event which passes the filter.
+    fn each<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64, Record<Vec<u8>>) -> io::Result<()>,
+    {
+        for (index, path) in journal_segments(&self.dir)? {
+            if self.filter.segment.is_some_and(|segment| segment != index) {
+                continue;
+            }
+            for record in Replay::segment(&path, Raw)? {
+                let record = record?;
+                if self.filter.accepts(index, &record) {
+                    f(index, record)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn segments(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:>8} {:>8} {:>12}  {:<27}  {:<27}",
+            "segment", "events", "bytes", "first", "last"
+        )?;
+        for (index, path) in journal_segments(&self.dir)? {
+            let mut events = 0;
+            let mut first = None;
+            let mut last = None;
+            for record in Replay::segment(&path, Raw)? {
+                let record = record?;
+                events += 1;
+                first = first.or(Some(record.time));
+                last = Some(record.time);
+            }
+            let time = |time: Option<SystemTime>| time.map_or("-".to_string(), format_time);
+            writeln!(
+                out,
+                "{:>8} {:>8} {:>12}  {:<27}  {:<27}",
+                index,
+                events,
+                fs::metadata(&path)?.len(),
+                time(first),
+                time(last)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut kinds: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        self.each(|_, record| {
+            let entry = kinds.entry(record.kind).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += record.event.len() as u64;
+            Ok(())
+        })?;
+        writeln!(out, "{:<24} {:>10} {:>12}", "kind", "events", "bytes")?;
+        let mut total = (0, 0);
+        for (kind, (events, bytes)) in kinds {
+            let kind = if kind.is_empty() { "-" } else { &kind };
+            writeln!(out, "{:<24} {:>10} {:>12}", kind, events, bytes)?;
+            total.0 += events;
+            total.1 += bytes;
+        }
+        writeln!(out, "{:<24} {:>10} {:>12}", "total", total.0, total.1)
+    }
+
+    fn tail(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut cursor = Cursor {
+            segment: 0,
+            replay: None,
+        };
+        let mut last = VecDeque::new();
+        self.read_new(&mut cursor, |segment, record| {
+            if last.len() == self.count {
+                last.pop_front();
+            }
+            if self.count > 0 {
+                last.push_back((segment, record));
+            }
+            Ok(())
+        })?;
+        for (segment, record) in last {
+            writeln!(out, "{}", text(segment, &record))?;
+        }
+        if !self.follow {
+            return Ok(());
+        }
+        loop {
+            out.flush()?;
+            thread::sleep(POLL);
+            self.read_new(&mut cursor, |segment, record| {
+                writeln!(out, "{}", text(segment, &record))
+            })?;
+        }
+    }
+
+    /// Calls `f` for the events which pass the filter and were written after `cursor`.
+    fn read_new<F>(&self, cursor: &mut Cursor, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64, Record<Vec<u8>>) -> io::Result<()>,
+    {
+        let segments = journal_segments(&self.dir)?;
+        let count = segments.len();
+        for (i, (index, path)) in segments.into_iter().enumerate() {
+            if index < cursor.segment {
+                continue;
+            }
+            if index > cursor.segment || cursor.replay.is_none() {
+                cursor.segment = index;
+                cursor.replay = Some(Replay::segment(&path, Raw)?);
+            }
+            for record in cursor.replay.as_mut().unwrap() {
+                let record = record?;
+                if self.filter.accepts(index, &record) {
+                    f(index, record)?;
+                }
+            }
+            // Segments are never written to once the next one is started.
+            if i + 1 < count {
+                cursor.segment = index + 1;
+                cursor.replay = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, out: &mut dyn Write) -> io::Result<()> {
+        let segments = journal_segments(&self.dir)?;
+        let keep = self.keep.unwrap_or(segments.len());
+        let removed = segments.len().saturating_sub(keep);
+        for (_, path) in &segments[..removed] {
+            fs::remove_file(path)?;
+            writeln!(out, "removed {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Position of `tail --follow` in the journal.
+struct Cursor {
+    // Number of the segment being read, or of the next one to read.
+    segment: u64,
+    // Reader of that segment, which returns the records appended to it since it was last read.
+    replay: Option<Replay<Vec<u8>, Raw>>,
+}
+
+/// Keeps the events as they were encoded.
+struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(&mut self, event: &Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(event);
+        Ok(())
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Formats an event as a line of text.
+fn text(segment: u64, record: &Record<Vec<u8>>) -> String {
+    let kind = if record.kind.is_empty() {
+        "-"
+    } else {
+        &record.kind
+    };
+    let event = match std::str::from_utf8(&record.event) {
+        Ok(text) => format!("{:?}", text),
+        Err(_) => format!("0x{}", hex(&record.event)),
+    };
+    format!(
+        "{}:{} {} {} {}",
+        segment,
+        record.seq,
+        format_time(record.time),
+        kind,
+        event
+    )
+}
+
+/// Formats an event as a JSON object.
+fn json(segment: u64, record: &Record<Vec<u8>>) -> String {
+    let nanos = record
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let event = match std::str::from_utf8(&record.event) {
+        Ok(text) => format!("\"text\":{}", json_string(text)),
+        Err(_) => format!("\"hex\":\"{}\"", hex(&record.event)),
+    };
+    format!(
+        "{{\"segment\":{},\"seq\":{},\"time\":\"{}\",\"time_ns\":{},\"kind\":{},\"bytes\":{},{}}}",
+        segment,
+        record.seq,
+        format_time(record.time),
+        nanos,
+        json_string(&record.kind),
+        record.event.len(),
+        event
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Formats `time` as an RFC 3339 timestamp in UTC, with microseconds.
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Converts days since the epoch to a date in the proleptic Gregorian calendar.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+fn invalid(message: String) -> Usage {
+    Usage::Invalid(message)
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, Usage> {
+    value.ok_or_else(|| invalid(format!("{} requires a value", option)))
+}
+
+fn number(option: &str, arg: Option<String>) -> Result<u64, Usage> {
+    let arg = value(option, arg)?;
+    arg.parse()
+        .map_err(|_| invalid(format!("{} requires a number, got `{}`", option, arg)))
+}
+
+fn seconds(option: &str, arg: Option<String>) -> Result<SystemTime, Usage> {
+    number(option, arg).map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
@@ -0,0 +1,159 @@
+use super::*;
+use mrogalski_looper::{run, Flow, FnHandler, Journal};
+
+struct Text;
+
+impl Codec<String> for Text {
+    fn encode(&mut self, event: &String, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(event.as_bytes());
+        Ok(())
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn kind(&mut self, event: &String) -> String {
+        event.split(' ').next().unwrap_or_default().to_string()
+    }
+}
+
+/// Writes a journal with one event per segment.
+fn journal(name: &str, events: &[&str]) -> PathBuf {
+    let dir = env::temp_dir().join(format!("looper-cli-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let events: Vec<String> = events.iter().map(|event| event.to_string()).collect();
+    let handler =
+        FnHandler::new((), |_, _: String, _| Flow::Continue).on_start(move |_, sender| {
+            for event in &events {
+                sender.send(event.clone()).unwrap();
+            }
+        });
+    let journal = Journal::new(handler, Text, &dir).unwrap().segment_size(1);
+    run(journal).1.unwrap();
+    dir
+}
+
+fn args(line: &str) -> Result<Args, Usage> {
+    Args::parse(line.split_whitespace().map(String::from))
+}
+
+fn output(line: &str) -> String {
+    let mut out = vec![];
+    args(line).unwrap().run(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn parse_args() {
+    let parsed = args("print dir --kind add --from 2 --since 10").unwrap();
+    assert_eq!(parsed.command, Command::Print);
+    assert_eq!(parsed.dir, PathBuf::from("dir"));
+    assert_eq!(parsed.filter.kind, Some("add".to_string()));
+    assert_eq!(parsed.filter.from, Some(2));
+    assert_eq!(
+        parsed.filter.since,
+        Some(UNIX_EPOCH + Duration::from_secs(10))
+    );
+    assert_eq!(args("--help"), Err(Usage::Help));
+    assert!(args("print").is_err());
+    assert!(args("print dir --from x").is_err());
+    assert!(args("truncate dir").is_err());
+    assert!(args("truncate dir --keep 0").is_err());
+}
+
+#[test]
+fn format_values() {
+    let time = UNIX_EPOCH + Duration::new(951_827_696, 123_456_789);
+    assert_eq!(format_time(time), "2000-02-29T12:34:56.123456Z");
+    assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+    assert_eq!(json_string("a\"b\\\n\u{1}"), "\"a\\\"b\\\\\\n\\u0001\"");
+    assert_eq!(hex(&[0, 15, 255]), "000fff");
+}
+
+#[test]
+fn inspect_journal() {
+    let dir = journal("inspect", &["add 1", "add 2", "remove 1", "add 3"]);
+    let path = dir.display();
+
+    let printed = output(&format!("print {} --kind add --from 1", path));
+    let lines: Vec<&str> = printed.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("1:1 "), "{}", lines[0]);
+    assert!(lines[0].ends_with(" add \"add 2\""), "{}", lines[0]);
+    assert!(lines[1].ends_with(" add \"add 3\""), "{}", lines[1]);
+
+    let exported = output(&format!("export {} --segment 2", path));
+    assert!(exported.starts_with("{\"segment\":2,\"seq\":2,\"time\":\""));
+    assert!(exported.ends_with("\"kind\":\"remove\",\"bytes\":8,\"text\":\"remove 1\"}\n"));
+
+    let stats = output(&format!("stats {}", path));
+    let stats: Vec<Vec<&str>> = stats
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(stats[1], vec!["add", "3", "15"]);
+    assert_eq!(stats[2], vec!["remove", "1", "8"]);
+    assert_eq!(stats[3], vec!["total", "4", "23"]);
+
+    let tail = output(&format!("tail {} -n 1", path));
+    assert!(tail.ends_with(" add \"add 3\"\n"), "{}", tail);
+    assert_eq!(output(&format!("segments {}", path)).lines().count(), 5);
+
+    output(&format!("truncate {} --keep 1", path));
+    assert_eq!(journal_segments(&dir).unwrap().len(), 1);
+    assert_eq!(
+        output(&format!("stats {}", path))
+            .lines()
+            .last()
+            .unwrap()
+            .split_whitespace()
+            .collect::<Vec<_>>(),
+        vec!["total", "1", "5"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn follow_reads_appended_events() {
+    let dir = journal("follow", &[]);
+    let handler = |events: &'static [&'static str]| {
+        FnHandler::new((), |_, _: String, _| Flow::Continue).on_start(move |_, sender| {
+            for event in events {
+                sender.send(event.to_string()).unwrap();
+            }
+        })
+    };
+    run(Journal::new(handler(&["add 1", "add 2"]), Text, &dir).unwrap())
+        .1
+        .unwrap();
+    let (_, segment) = journal_segments(&dir).unwrap().remove(0);
+    let written = fs::read(&segment).unwrap();
+    // Both records have the same size. The second one is cut short as if it was being written.
+    let record = (written.len() - 8) / 2;
+    fs::write(&segment, &written[..8 + record + 3]).unwrap();
+
+    let args = args(&format!("tail {}", dir.display())).unwrap();
+    let mut cursor = Cursor {
+        segment: 0,
+        replay: None,
+    };
+    let read = |cursor: &mut Cursor| {
+        let mut events = vec![];
+        args.read_new(cursor, |segment, record| {
+            events.push((segment, String::from_utf8(record.event).unwrap()));
+            Ok(())
+        })
+        .unwrap();
+        events
+    };
+    assert_eq!(read(&mut cursor), vec![(0, "add 1".to_string())]);
+    fs::write(&segment, &written).unwrap();
+    assert_eq!(read(&mut cursor), vec![(0, "add 2".to_string())]);
+    assert_eq!(read(&mut cursor), vec![]);
+    run(Journal::new(handler(&["add 3"]), Text, &dir).unwrap())
+        .1
+        .unwrap();
+    assert_eq!(read(&mut cursor), vec![(1, "add 3".to_string())]);
+    fs::remove_dir_all(&dir).unwrap();
+}
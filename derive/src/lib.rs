@@ -0,0 +1,510 @@
+//! Derive macro for the `mrogalski-looper` crate. Enable the `derive` feature of `mrogalski-looper`
+//! and use it as `mrogalski_looper::Dispatch`.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+use std::iter::FromIterator;
+
+/// Path under which the generated code refers to the main crate.
+const LOOPER: &str = "::mrogalski_looper";
+
+/// Dispatches the variants of an event enum to the methods of a handler.
+///
+/// For an enum `Event`, generates an `EventHandler` trait with an `on_<variant>` method for every
+/// variant, which receives the fields of the variant and the `Context`. The trait also has the
+/// `Output` type and the `start`, `idle` and `end` methods of `Handler`. Every type listed in the
+/// `#[dispatch(...)]` attribute of the enum gets a `Handler<Event>` implementation which calls the
+/// methods of its `EventHandler` implementation.
+///
+/// Variants marked with `#[call]` must have a `Reply<T>` as their last field. Their `on_<variant>`
+/// methods return `T`, which is sent back through the reply. The generated `EventCalls` trait,
+/// implemented for `Sender<Event>`, has a `call_<variant>` method for each of them, which sends
+/// the request and waits for the reply.
+///
+/// ```rust
+/// extern crate mrogalski_looper;
+///
+/// use mrogalski_looper::{Context, Dispatch, Flow, Reply, Sender, spawn};
+///
+/// #[derive(Dispatch)]
+/// #[dispatch(Counter)]
+/// enum Event {
+///     Add(u32),
+///     Reset,
+///     #[call]
+///     Get(Reply<u32>),
+/// }
+///
+/// struct Counter(u32);
+///
+/// impl EventHandler for Counter {
+///     type Output = u32;
+///     fn on_add(&mut self, n: u32, _: &mut Context<Event>) -> Flow {
+///         self.0 += n;
+///         Flow::Continue
+///     }
+///     fn on_reset(&mut self, _: &mut Context<Event>) -> Flow {
+///         self.0 = 0;
+///         Flow::Continue
+///     }
+///     fn on_get(&mut self, _: &mut Context<Event>) -> u32 {
+///         self.0
+///     }
+///     fn end(self) -> u32 {
+///         self.0
+///     }
+/// }
+///
+/// let looper = spawn(Counter(0));
+/// looper.sender().send(Event::Add(2)).unwrap();
+/// looper.sender().send(Event::Add(3)).unwrap();
+/// assert_eq!(looper.sender().call_get(), Ok(5));
+/// looper.sender().send(Event::Reset).unwrap();
+/// assert_eq!(looper.join().unwrap().1, 0);
+/// ```
+#[proc_macro_derive(Dispatch, attributes(dispatch, call))]
+pub fn derive_dispatch(input: TokenStream) -> TokenStream {
+    let code = match Enum::parse(input) {
+        Ok(item) => item.expand(),
+        Err(message) => format!("compile_error!({:?});", message),
+    };
+    code.parse().expect("generated code is invalid")
+}
+
+struct Enum {
+    vis: String,
+    name: String,
+    handlers: Vec<String>,
+    variants: Vec<Variant>,
+}
+
+struct Variant {
+    name: String,
+    fields: Fields,
+    // Type of the reply, for variants marked with `#[call]`.
+    reply: Option<String>,
+}
+
+enum Fields {
+    Unit,
+    Tuple(Vec<String>),
+    Named(Vec<(String, String)>),
+}
+
+impl Enum {
+    fn parse(input: TokenStream) -> Result<Enum, String> {
+        let mut tokens = input.into_iter().peekable();
+        let mut vis = String::new();
+        let mut handlers = vec![];
+        loop {
+            match tokens.next() {
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == '#' => {
+                    if let Some(TokenTree::Group(attr)) = tokens.next() {
+                        if let Some(list) = attribute(&attr.stream(), "dispatch") {
+                            handlers.extend(split(list).into_iter().map(stringify));
+                        }
+                    }
+                }
+                Some(TokenTree::Ident(ref ident)) if ident.to_string() == "pub" => {
+                    vis = "pub".to_string();
+                    if let Some(TokenTree::Group(group)) = tokens.peek() {
+                        if group.delimiter() == Delimiter::Parenthesis {
+                            vis.push_str(&group.to_string());
+                            tokens.next();
+                        }
+                    }
+                }
+                Some(TokenTree::Ident(ref ident)) if ident.to_string() == "enum" => break,
+                Some(_) => {}
+                None => return Err("Dispatch can only be derived for enums".to_string()),
+            }
+        }
+        let name = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ => return Err("expected the name of the enum".to_string()),
+        };
+        let body = match tokens.next() {
+            Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Brace => {
+                group.stream()
+            }
+            _ => return Err("Dispatch can't be derived for generic enums".to_string()),
+        };
+        let variants = split(body)
+            .into_iter()
+            .map(Variant::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Enum {
+            vis,
+            name,
+            handlers,
+            variants,
+        })
+    }
+
+    fn expand(&self) -> String {
+        let mut code = self.handler_trait();
+        for handler in &self.handlers {
+            code.push_str(&self.handler_impl(handler));
+        }
+        if self.variants.iter().any(|variant| variant.reply.is_some()) {
+            code.push_str(&self.calls());
+        }
+        code
+    }
+
+    /// Trait with a method for every variant, implemented by the user.
+    fn handler_trait(&self) -> String {
+        let name = &self.name;
+        let mut methods = String::new();
+        for variant in &self.variants {
+            let params: String = variant
+                .args()
+                .iter()
+                .map(|(arg, ty)| format!("{}: {}, ", arg, ty))
+                .collect();
+            let output = match variant.reply {
+                Some(ref reply) => reply.clone(),
+                None => format!("{}::Flow", LOOPER),
+            };
+            methods.push_str(&format!(
+                "/// Called for every `{name}::{variant}` event.\n\
+                 fn on_{method}(&mut self, {params}__looper_context: &mut {looper}::Context<{name}>) \
+                 -> {output};\n",
+                name = name,
+                variant = variant.name,
+                method = snake_case(&variant.name),
+                params = params,
+                looper = LOOPER,
+                output = output,
+            ));
+        }
+        format!(
+            "/// Handles `{name}` events. Generated by `#[derive(Dispatch)]`.\n\
+             {vis} trait {name}Handler: Sized {{\n\
+                 /// Value produced by `end`. See `Handler::Output`.\n\
+                 type Output;\n\
+                 /// See `Handler::start`.\n\
+                 fn start(&mut self, sender: {looper}::Sender<{name}>) {{\n\
+                     let _ = sender;\n\
+                 }}\n\
+                 {methods}\
+                 /// See `Handler::idle`.\n\
+                 fn idle(&mut self) -> {looper}::Idle {{\n\
+                     {looper}::Idle::Wait\n\
+                 }}\n\
+                 /// See `Handler::end`.\n\
+                 fn end(self) -> Self::Output;\n\
+             }}\n",
+            vis = self.vis,
+            name = name,
+            looper = LOOPER,
+            methods = methods,
+        )
+    }
+
+    /// `Handler` implementation which forwards the events to the generated trait.
+    fn handler_impl(&self, handler: &str) -> String {
+        let name = &self.name;
+        let mut arms = String::new();
+        for variant in &self.variants {
+            let args: String = variant
+                .args()
+                .iter()
+                .map(|(arg, _)| format!("{}, ", arg))
+                .collect();
+            let call = format!(
+                "{name}Handler::on_{method}(self, {args}__looper_context)",
+                name = name,
+                method = snake_case(&variant.name),
+                args = args,
+            );
+            let body = match variant.reply {
+                Some(_) => format!(
+                    "{{ __looper_reply.send({call}); {looper}::Flow::Continue }}",
+                    call = call,
+                    looper = LOOPER,
+                ),
+                None => call,
+            };
+            arms.push_str(&format!("{} => {},\n", variant.pattern(name), body));
+        }
+        format!(
+            "impl {looper}::Handler<{name}> for {handler} {{\n\
+                 type Output = <{handler} as {name}Handler>::Output;\n\
+                 fn start(&mut self, __looper_sender: {looper}::Sender<{name}>) {{\n\
+                     {name}Handler::start(self, __looper_sender)\n\
+                 }}\n\
+                 fn handle(&mut self, __looper_event: {name}, \
+                     __looper_context: &mut {looper}::Context<{name}>) -> {looper}::Flow {{\n\
+                     match __looper_event {{\n{arms}}}\n\
+                 }}\n\
+                 fn idle(&mut self) -> {looper}::Idle {{\n\
+                     {name}Handler::idle(self)\n\
+                 }}\n\
+                 fn end(self) -> Self::Output {{\n\
+                     {name}Handler::end(self)\n\
+                 }}\n\
+             }}\n",
+            looper = LOOPER,
+            name = name,
+            handler = handler,
+            arms = arms,
+        )
+    }
+
+    /// Trait with a blocking method for every `#[call]` variant, implemented for the `Sender`.
+    fn calls(&self) -> String {
+        let name = &self.name;
+        let mut methods = String::new();
+        let mut impls = String::new();
+        for variant in &self.variants {
+            let reply = match variant.reply {
+                Some(ref reply) => reply,
+                None => continue,
+            };
+            let args = variant.args();
+            let params: String = args
+                .iter()
+                .map(|(arg, ty)| format!(", {}: {}", arg, ty))
+                .collect();
+            let signature = format!(
+                "fn call_{method}(&self{params}) \
+                 -> ::std::result::Result<{reply}, {looper}::CallError>",
+                method = snake_case(&variant.name),
+                params = params,
+                reply = reply,
+                looper = LOOPER,
+            );
+            methods.push_str(&format!(
+                "/// Sends `{name}::{variant}` and blocks until the handler replies.\n{signature};\n",
+                name = name,
+                variant = variant.name,
+                signature = signature,
+            ));
+            impls.push_str(&format!(
+                "{signature} {{\n\
+                     self.call(move |__looper_reply| {pattern})\n\
+                 }}\n",
+                signature = signature,
+                pattern = variant.pattern(name),
+            ));
+        }
+        format!(
+            "/// Calls to the `{name}` event loop. Generated by `#[derive(Dispatch)]`.\n\
+             {vis} trait {name}Calls {{\n{methods}}}\n\
+             impl {name}Calls for {looper}::Sender<{name}> {{\n{impls}}}\n",
+            vis = self.vis,
+            name = name,
+            looper = LOOPER,
+            methods = methods,
+            impls = impls,
+        )
+    }
+}
+
+impl Variant {
+    fn parse(tokens: Vec<TokenTree>) -> Result<Variant, String> {
+        let mut tokens = tokens.into_iter();
+        let mut call = false;
+        let name = loop {
+            match tokens.next() {
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == '#' => {
+                    if let Some(TokenTree::Group(attr)) = tokens.next() {
+                        call |= attribute(&attr.stream(), "call").is_some();
+                    }
+                }
+                Some(TokenTree::Ident(ident)) => break ident.to_string(),
+                _ => return Err("expected a variant".to_string()),
+            }
+        };
+        let fields = match tokens.next() {
+            Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Parenthesis => {
+                let types = split(group.stream())
+                    .into_iter()
+                    .map(|field| stringify(skip_attributes(field)))
+                    .collect();
+                Fields::Tuple(types)
+            }
+            Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Brace => {
+                let fields = split(group.stream())
+                    .into_iter()
+                    .map(|field| {
+                        let mut field = skip_attributes(field).into_iter();
+                        let name = field.next().map(|name| name.to_string());
+                        field.next();
+                        (name.unwrap_or_default(), stringify(field.collect()))
+                    })
+                    .collect();
+                Fields::Named(fields)
+            }
+            _ => Fields::Unit,
+        };
+        let reply = if call {
+            let last = match fields {
+                Fields::Tuple(ref types) => types.last(),
+                Fields::Named(ref fields) => fields.last().map(|(_, ty)| ty),
+                Fields::Unit => None,
+            };
+            match last.and_then(|ty| reply_type(ty)) {
+                Some(reply) => Some(reply),
+                None => {
+                    return Err(format!(
+                        "the last field of the `#[call]` variant `{}` must be a `Reply<T>`",
+                        name
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+        Ok(Variant {
+            name,
+            fields,
+            reply,
+        })
+    }
+
+    /// Names and types of the fields passed to the `on_<variant>` method. Excludes the reply.
+    ///
+    /// Tuple fields are named `__looper_arg<i>`. Like the other bindings in the generated code, the
+    /// names are prefixed so that they can't clash with the names of the fields.
+    fn args(&self) -> Vec<(String, String)> {
+        let mut args: Vec<(String, String)> = match self.fields {
+            Fields::Unit => vec![],
+            Fields::Tuple(ref types) => types
+                .iter()
+                .enumerate()
+                .map(|(i, ty)| (format!("__looper_arg{}", i), ty.clone()))
+                .collect(),
+            Fields::Named(ref fields) => fields.clone(),
+        };
+        if self.reply.is_some() {
+            args.pop();
+        }
+        args
+    }
+
+    /// Pattern which binds the fields to the names returned by `args`, and the reply to
+    /// `__looper_reply`.
+    fn pattern(&self, name: &str) -> String {
+        let mut fields: Vec<String> = self.args().into_iter().map(|(arg, _)| arg).collect();
+        match self.fields {
+            Fields::Unit => format!("{}::{}", name, self.name),
+            Fields::Tuple(_) => {
+                if self.reply.is_some() {
+                    fields.push("__looper_reply".to_string());
+                }
+                format!("{}::{}({})", name, self.name, fields.join(", "))
+            }
+            Fields::Named(ref named) => {
+                if self.reply.is_some() {
+                    let last = &named[named.len() - 1].0;
+                    fields.push(format!("{}: __looper_reply", last));
+                }
+                format!("{}::{} {{ {} }}", name, self.name, fields.join(", "))
+            }
+        }
+    }
+}
+
+/// Returns the arguments of an attribute with the given name, such as `dispatch(A, B)`.
+fn attribute(attr: &TokenStream, name: &str) -> Option<TokenStream> {
+    let mut tokens = attr.clone().into_iter();
+    match tokens.next() {
+        Some(TokenTree::Ident(ref ident)) if ident.to_string() == name => {}
+        _ => return None,
+    }
+    match tokens.next() {
+        Some(TokenTree::Group(group)) => Some(group.stream()),
+        _ => Some(TokenStream::new()),
+    }
+}
+
+/// Splits `tokens` on the commas which are not nested in brackets, including angle brackets.
+fn split(tokens: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![vec![]];
+    let mut depth = 0;
+    let mut arrow = false;
+    for token in tokens {
+        let mut dash = false;
+        if let TokenTree::Punct(ref punct) = token {
+            match punct.as_char() {
+                '<' => depth += 1,
+                // The `>` of `->` doesn't close an angle bracket.
+                '>' if !arrow => depth -= 1,
+                ',' if depth == 0 => {
+                    parts.push(vec![]);
+                    continue;
+                }
+                '-' => dash = true,
+                _ => {}
+            }
+        }
+        arrow = dash;
+        parts.last_mut().unwrap().push(token);
+    }
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+/// Removes the attributes and the visibility in front of a field.
+fn skip_attributes(field: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut tokens = field.into_iter().peekable();
+    loop {
+        match tokens.peek() {
+            Some(TokenTree::Punct(punct)) if punct.as_char() == '#' => {
+                tokens.next();
+                tokens.next();
+            }
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "pub" => {
+                tokens.next();
+                if let Some(TokenTree::Group(group)) = tokens.peek() {
+                    if group.delimiter() == Delimiter::Parenthesis {
+                        tokens.next();
+                    }
+                }
+            }
+            _ => return tokens.collect(),
+        }
+    }
+}
+
+fn stringify(tokens: Vec<TokenTree>) -> String {
+    TokenStream::from_iter(tokens).to_string()
+}
+
+/// Returns `T` if `ty` is `Reply<T>`, possibly with a path in front.
+fn reply_type(ty: &str) -> Option<String> {
+    let ty: TokenStream = ty.parse().ok()?;
+    let tokens: Vec<TokenTree> = ty.into_iter().collect();
+    let start = tokens.iter().position(|token| match *token {
+        TokenTree::Ident(ref ident) => ident.to_string() == "Reply",
+        _ => false,
+    })?;
+    match (tokens.get(start + 1), tokens.last()) {
+        (Some(TokenTree::Punct(open)), Some(TokenTree::Punct(close)))
+            if open.as_char() == '<' && close.as_char() == '>' =>
+        {
+            Some(stringify(tokens[start + 2..tokens.len() - 1].to_vec()))
+        }
+        _ => None,
+    }
+}
+
+/// Converts a variant name such as `HttpRequest` or `HTTPRequest` to `http_request`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let after_lower = chars[i - 1].is_lowercase() || chars[i - 1].is_numeric();
+            let before_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if after_lower || (chars[i - 1].is_uppercase() && before_lower) {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
@@ -0,0 +1,161 @@
+extern crate mrogalski_looper;
+
+use mrogalski_looper::{spawn, CallError, Context, Dispatch, Flow, Reply, Sender};
+
+#[derive(Dispatch)]
+#[dispatch(Store, Strict)]
+enum Command {
+    Put {
+        key: String,
+        value: u32,
+    },
+    Remove(String),
+    #[call]
+    Get(String, Reply<Option<u32>>),
+    #[call]
+    Sum {
+        reply: Reply<u32>,
+    },
+    #[call]
+    HTTPStatus(Reply<u16>),
+    // Field names which match the names used by the generated code.
+    #[call]
+    Total {
+        context: u32,
+        reply: u32,
+        event: u32,
+        answer: Reply<u32>,
+    },
+    Quit,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: Vec<(String, u32)>,
+    started: bool,
+}
+
+impl CommandHandler for Store {
+    type Output = Vec<(String, u32)>;
+
+    fn start(&mut self, _: Sender<Command>) {
+        self.started = true;
+    }
+
+    fn on_put(&mut self, key: String, value: u32, _: &mut Context<Command>) -> Flow {
+        self.entries.retain(|entry| entry.0 != key);
+        self.entries.push((key, value));
+        Flow::Continue
+    }
+
+    fn on_remove(&mut self, key: String, _: &mut Context<Command>) -> Flow {
+        self.entries.retain(|entry| entry.0 != key);
+        Flow::Continue
+    }
+
+    fn on_get(&mut self, key: String, _: &mut Context<Command>) -> Option<u32> {
+        self.entries
+            .iter()
+            .find(|entry| entry.0 == key)
+            .map(|entry| entry.1)
+    }
+
+    fn on_sum(&mut self, _: &mut Context<Command>) -> u32 {
+        self.entries.iter().map(|entry| entry.1).sum()
+    }
+
+    fn on_http_status(&mut self, _: &mut Context<Command>) -> u16 {
+        if self.started {
+            200
+        } else {
+            500
+        }
+    }
+
+    fn on_total(&mut self, context: u32, reply: u32, event: u32, _: &mut Context<Command>) -> u32 {
+        context + reply + event
+    }
+
+    fn on_quit(&mut self, context: &mut Context<Command>) -> Flow {
+        context.drain();
+        Flow::Continue
+    }
+
+    fn end(self) -> Vec<(String, u32)> {
+        self.entries
+    }
+}
+
+/// Refuses to remove entries, which terminates its event loop.
+struct Strict(Store);
+
+impl CommandHandler for Strict {
+    type Output = Vec<(String, u32)>;
+
+    fn on_put(&mut self, key: String, value: u32, context: &mut Context<Command>) -> Flow {
+        self.0.on_put(key, value, context)
+    }
+
+    fn on_remove(&mut self, _: String, _: &mut Context<Command>) -> Flow {
+        Flow::Stop
+    }
+
+    fn on_get(&mut self, key: String, context: &mut Context<Command>) -> Option<u32> {
+        self.0.on_get(key, context)
+    }
+
+    fn on_sum(&mut self, context: &mut Context<Command>) -> u32 {
+        self.0.on_sum(context)
+    }
+
+    fn on_http_status(&mut self, _: &mut Context<Command>) -> u16 {
+        403
+    }
+
+    fn on_total(&mut self, a: u32, b: u32, c: u32, context: &mut Context<Command>) -> u32 {
+        self.0.on_total(a, b, c, context)
+    }
+
+    fn on_quit(&mut self, context: &mut Context<Command>) -> Flow {
+        self.0.on_quit(context)
+    }
+
+    fn end(self) -> Vec<(String, u32)> {
+        self.0.end()
+    }
+}
+
+fn put(key: &str, value: u32) -> Command {
+    Command::Put {
+        key: key.to_string(),
+        value,
+    }
+}
+
+#[test]
+fn dispatch_variants() {
+    let looper = spawn(Store::default());
+    let sender = looper.sender().clone();
+    sender.send(put("a", 1)).unwrap();
+    sender.send(put("b", 2)).unwrap();
+    sender.send(put("a", 3)).unwrap();
+    assert_eq!(sender.call_get("a".to_string()), Ok(Some(3)));
+    assert_eq!(sender.call_sum(), Ok(5));
+    sender.send(Command::Remove("a".to_string())).unwrap();
+    assert_eq!(sender.call_get("a".to_string()), Ok(None));
+    assert_eq!(sender.call_http_status(), Ok(200));
+    assert_eq!(sender.call_total(1, 2, 3), Ok(6));
+    sender.send(Command::Quit).unwrap();
+    assert_eq!(looper.join().unwrap().1, vec![("b".to_string(), 2)]);
+    assert_eq!(sender.call_sum(), Err(CallError::Disconnected));
+}
+
+#[test]
+fn dispatch_to_several_handlers() {
+    let looper = spawn(Strict(Store::default()));
+    let sender = looper.sender().clone();
+    sender.send(put("a", 1)).unwrap();
+    assert_eq!(sender.call_http_status(), Ok(403));
+    sender.send(Command::Remove("a".to_string())).unwrap();
+    assert_eq!(looper.join().unwrap().1, vec![("a".to_string(), 1)]);
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Events delivered to `Handler::handle_batch` in a single wakeup of the event loop.
+///
+/// Events are taken out by iterating over the batch. Events which are still in the batch when
+/// `handle_batch` returns are passed to its next call, unless the event loop terminates.
+pub struct Batch<EVENT> {
+    events: VecDeque<EVENT>,
+}
+
+impl<EVENT> Batch<EVENT> {
+    pub(crate) fn new() -> Batch<EVENT> {
+        Batch {
+            events: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: EVENT) {
+        self.events.push_back(event);
+    }
+
+    /// Number of events remaining in the batch.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if all events were taken out of the batch.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the next event without taking it out of the batch.
+    pub fn peek(&self) -> Option<&EVENT> {
+        self.events.front()
+    }
+}
+
+impl<EVENT> Iterator for Batch<EVENT> {
+    type Item = EVENT;
+
+    fn next(&mut self) -> Option<EVENT> {
+        self.events.pop_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len(), Some(self.len()))
+    }
+}
+
+impl<EVENT> ExactSizeIterator for Batch<EVENT> {}
+
+impl<EVENT> fmt::Debug for Batch<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Batch").field("len", &self.len()).finish()
+    }
+}
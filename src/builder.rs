@@ -0,0 +1,235 @@
+use std::fmt;
+use std::io;
+use std::thread;
+
+use super::event_loop::{event_loop, Options};
+use super::invoker::{Invoker, Jobs};
+use super::mailbox::{channel, Overflow, Sender};
+use super::{Exit, Handler, PanicPolicy};
+
+/// Configures an event loop before running it.
+///
+/// `run` and `spawn` are shortcuts for running an event loop with the default configuration.
+///
+/// ```rust
+/// use mrogalski_looper::{Builder, Context, Exit, Flow, Handler, Sender};
+///
+/// struct Sum(i32);
+///
+/// impl Handler<i32> for Sum {
+///     type Output = i32;
+///     fn start(&mut self, _: Sender<i32>) {}
+///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+///         self.0 += i;
+///         Flow::Continue
+///     }
+///     fn end(self) -> i32 {
+///         self.0
+///     }
+/// }
+///
+/// let looper = Builder::new().name("sum".to_string()).spawn(Sum(0)).unwrap();
+/// for i in 1..4 {
+///     looper.sender().send(i).unwrap();
+/// }
+/// assert_eq!(looper.join().unwrap(), (Exit::Disconnected, 6));
+/// ```
+#[derive(Debug)]
+pub struct Builder {
+    name: Option<String>,
+    capacity: Option<usize>,
+    overflow: Overflow,
+    options: Options,
+}
+
+impl Builder {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Builder {
+        Builder {
+            name: None,
+            capacity: None,
+            overflow: Overflow::default(),
+            options: Options {
+                max_batch: 1,
+                panic: PanicPolicy::default(),
+            },
+        }
+    }
+
+    /// Names the thread of a spawned event loop. Ignored by `Builder::run`.
+    pub fn name(mut self, name: String) -> Builder {
+        self.name = Some(name);
+        self
+    }
+
+    /// Limits the number of events waiting in the mailbox of the event loop.
+    ///
+    /// By default the mailbox is unbounded. What happens to events sent to a full mailbox is decided
+    /// by the `overflow` policy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn capacity(mut self, capacity: usize) -> Builder {
+        assert!(capacity > 0, "mailbox capacity must be greater than zero");
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Sets what happens to events sent to a full mailbox. Defaults to `Overflow::Block`.
+    ///
+    /// Has no effect unless `capacity` is set.
+    pub fn overflow(mut self, overflow: Overflow) -> Builder {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Limits the number of events passed to a single `Handler::handle_batch` call. Defaults to one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch` is zero.
+    pub fn batch(mut self, batch: usize) -> Builder {
+        assert!(batch > 0, "batch size must be greater than zero");
+        self.options.max_batch = batch;
+        self
+    }
+
+    /// Sets what happens when the handler panics. Defaults to `PanicPolicy::Stop`.
+    pub fn on_panic(mut self, policy: PanicPolicy) -> Builder {
+        self.options.panic = policy;
+        self
+    }
+
+    /// Runs the event loop on the current thread.
+    ///
+    /// Returns the reason for which the event loop terminated and the value produced by `Handler::end`.
+    pub fn run<EVENT: Send, HANDLER: Handler<EVENT>>(
+        self,
+        handler: HANDLER,
+    ) -> (Exit, HANDLER::Output) {
+        self.run_with(handler, |_, _| {})
+    }
+
+    /// Runs the event loop on the current thread, passing an `Invoker` to `setup` first.
+    ///
+    /// `setup` is called before `Handler::start`, with the handler and an invoker connected to the
+    /// event loop. The invoker can be handed to other threads or stored in the handler. Like a
+    /// `Sender`, it keeps the event loop alive until it's dropped.
+    ///
+    /// ```rust
+    /// use mrogalski_looper::{Builder, Context, Exit, Flow, Handler, Sender};
+    /// use std::thread;
+    ///
+    /// struct Sum(i32);
+    ///
+    /// impl Handler<i32> for Sum {
+    ///     type Output = i32;
+    ///     fn start(&mut self, _: Sender<i32>) {}
+    ///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+    ///         self.0 += i;
+    ///         Flow::Continue
+    ///     }
+    ///     fn end(self) -> i32 {
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let result = Builder::new().run_with(Sum(0), |_, invoker| {
+    ///     thread::spawn(move || {
+    ///         invoker.sender().send(2).unwrap();
+    ///         assert_eq!(invoker.call(|sum| sum.0 * 10), Ok(20));
+    ///     });
+    /// });
+    /// assert_eq!(result, (Exit::Disconnected, 2));
+    /// ```
+    pub fn run_with<EVENT, HANDLER, F>(
+        self,
+        mut handler: HANDLER,
+        setup: F,
+    ) -> (Exit, HANDLER::Output)
+    where
+        EVENT: Send,
+        HANDLER: Handler<EVENT>,
+        F: FnOnce(&mut HANDLER, Invoker<EVENT, HANDLER>),
+    {
+        let (sender, rx) = channel(self.capacity, self.overflow);
+        let jobs = Jobs::new();
+        setup(&mut handler, Invoker::new(sender.clone(), jobs.clone()));
+        event_loop(handler, sender, rx, &jobs, self.options)
+    }
+
+    /// Runs the event loop on a new thread.
+    ///
+    /// `Handler::start` is called on the new thread. Fails if the thread could not be created.
+    pub fn spawn<EVENT, HANDLER>(self, handler: HANDLER) -> io::Result<JoinHandle<EVENT, HANDLER>>
+    where
+        EVENT: Send + 'static,
+        HANDLER: Handler<EVENT> + Send + 'static,
+        HANDLER::Output: Send + 'static,
+    {
+        let mut thread = thread::Builder::new();
+        if let Some(name) = self.name {
+            thread = thread.name(name);
+        }
+        let (sender, rx) = channel(self.capacity, self.overflow);
+        let loop_sender = sender.clone();
+        let jobs = Jobs::new();
+        let invoker = Invoker::new(sender, jobs.clone());
+        let options = self.options;
+        let thread = thread.spawn(move || event_loop(handler, loop_sender, rx, &jobs, options))?;
+        Ok(JoinHandle { invoker, thread })
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+/// Owned permission to send events to, and join on, an event loop running on its own thread.
+///
+/// Returned by `spawn` and `Builder::spawn`.
+pub struct JoinHandle<EVENT: Send, HANDLER: Handler<EVENT>> {
+    invoker: Invoker<EVENT, HANDLER>,
+    thread: thread::JoinHandle<(Exit, HANDLER::Output)>,
+}
+
+impl<EVENT: Send, HANDLER: Handler<EVENT>> JoinHandle<EVENT, HANDLER> {
+    /// Sender connected to the event loop.
+    ///
+    /// The handle keeps its own sender alive until `join` is called.
+    pub fn sender(&self) -> &Sender<EVENT> {
+        self.invoker.sender()
+    }
+
+    /// Invoker which runs closures with access to the handler on the event loop thread.
+    ///
+    /// The handle keeps its own invoker alive until `join` is called.
+    pub fn invoker(&self) -> &Invoker<EVENT, HANDLER> {
+        &self.invoker
+    }
+
+    /// Thread on which the event loop runs.
+    pub fn thread(&self) -> &thread::Thread {
+        self.thread.thread()
+    }
+
+    /// Drops the sender and invoker held by this handle and waits for the event loop to terminate.
+    ///
+    /// Returns the reason for which the event loop terminated and the value produced by
+    /// `Handler::end`, or the panic payload if the event loop thread panicked.
+    pub fn join(self) -> thread::Result<(Exit, HANDLER::Output)> {
+        drop(self.invoker);
+        self.thread.join()
+    }
+}
+
+impl<EVENT: Send, HANDLER: Handler<EVENT>> fmt::Debug for JoinHandle<EVENT, HANDLER> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("thread", self.thread())
+            .finish()
+    }
+}
@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use super::mailbox::SendError;
+
+/// Error returned when a call made with `Sender::call` or `Sender::ask` didn't produce a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallError {
+    /// The event loop had already terminated, so the request was not sent.
+    Disconnected,
+    /// The mailbox is full and its `Overflow` policy is `Overflow::Fail`.
+    Full,
+    /// The request was sent but its `Reply` was dropped without a response. Happens when the event
+    /// loop terminates before handling the request.
+    NoReply,
+    /// No response arrived within the given timeout.
+    Timeout,
+}
+
+impl<EVENT> From<SendError<EVENT>> for CallError {
+    fn from(error: SendError<EVENT>) -> CallError {
+        match error {
+            SendError::Disconnected(_) => CallError::Disconnected,
+            SendError::Full(_) => CallError::Full,
+            // A response to an event scheduled that far ahead could never arrive in time.
+            SendError::DelayTooLong(_) => CallError::Timeout,
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CallError::Disconnected => "calling a terminated event loop",
+            CallError::Full => "calling an event loop with a full mailbox",
+            CallError::NoReply => "event loop terminated before replying",
+            CallError::Timeout => "timed out waiting for a reply",
+        })
+    }
+}
+
+impl Error for CallError {}
+
+/// Handle used by the handler to respond to a call. Sent to the event loop inside the request.
+///
+/// Dropping it without calling `send` fails the call with `CallError::NoReply`.
+pub struct Reply<T> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> Reply<T> {
+    /// Hands `value` to the caller.
+    pub fn send(self, value: T) {
+        self.slot.lock().value = Some(value);
+        // The caller is woken up when `self` is dropped.
+    }
+}
+
+impl<T> Drop for Reply<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.slot.lock();
+            state.replied = true;
+            self.slot.ready.notify_all();
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Reply<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Reply { .. }")
+    }
+}
+
+/// Response to a call made with `Sender::ask`, which may not have arrived yet.
+///
+/// Can be waited for by blocking the current thread or polled as a `Future`.
+pub struct Pending<T> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> Pending<T> {
+    /// Blocks until the response arrives.
+    pub fn wait(self) -> Result<T, CallError> {
+        let mut state = self.slot.lock();
+        while !state.replied {
+            state = self.slot.ready.wait(state).unwrap();
+        }
+        state.value.take().ok_or(CallError::NoReply)
+    }
+
+    /// Blocks until the response arrives or `timeout` elapses.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<T, CallError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.slot.lock();
+        while !state.replied {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(CallError::Timeout);
+            }
+            state = self
+                .slot
+                .ready
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        state.value.take().ok_or(CallError::NoReply)
+    }
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T, CallError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, CallError>> {
+        let mut state = self.slot.lock();
+        if state.replied {
+            Poll::Ready(state.value.take().ok_or(CallError::NoReply))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pending<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pending")
+            .field("replied", &self.slot.lock().replied)
+            .finish()
+    }
+}
+
+/// Creates a connected `Reply` and `Pending` pair.
+pub(crate) fn reply<T>() -> (Reply<T>, Pending<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(State {
+            value: None,
+            replied: false,
+            waker: None,
+        }),
+        ready: Condvar::new(),
+    });
+    (Reply { slot: slot.clone() }, Pending { slot })
+}
+
+struct State<T> {
+    value: Option<T>,
+    // Set once the `Reply` is gone, whether or not it produced a value.
+    replied: bool,
+    waker: Option<Waker>,
+}
+
+struct Slot<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+}
+
+impl<T> Slot<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+}
@@ -0,0 +1,453 @@
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+use super::context::Context;
+use super::mailbox::Sender;
+use super::{Flow, Handler, Idle};
+
+/// Value of one of two types. Handled by `Split` and returned by `Chain`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Either<L, R> {
+    /// Value of the first type.
+    Left(L),
+    /// Value of the second type.
+    Right(R),
+}
+
+/// Handler which passes on only the events accepted by a predicate.
+///
+/// Rejected events are dropped and count as `Flow::Continue`. Accepted events are passed to
+/// `Handler::handle` of the inner handler one by one.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Filter, Flow, Handler, Sender, run};
+///
+/// struct Collect(Vec<i32>);
+///
+/// impl Handler<i32> for Collect {
+///     type Output = Vec<i32>;
+///     fn start(&mut self, sender: Sender<i32>) {
+///         for i in 0..6 {
+///             sender.send(i).unwrap();
+///         }
+///     }
+///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+///         self.0.push(i);
+///         Flow::Continue
+///     }
+///     fn end(self) -> Vec<i32> {
+///         self.0
+///     }
+/// }
+///
+/// let even = Filter::new(Collect(vec![]), |i: &i32| i % 2 == 0);
+/// assert_eq!(run(even).1, vec![0, 2, 4]);
+/// ```
+#[derive(Debug)]
+pub struct Filter<HANDLER, PREDICATE> {
+    handler: HANDLER,
+    predicate: PREDICATE,
+}
+
+impl<HANDLER, PREDICATE> Filter<HANDLER, PREDICATE> {
+    /// Creates a handler which passes the events for which `predicate` returns `true` to `handler`.
+    pub fn new(handler: HANDLER, predicate: PREDICATE) -> Filter<HANDLER, PREDICATE> {
+        Filter { handler, predicate }
+    }
+}
+
+impl<EVENT, HANDLER, PREDICATE> Handler<EVENT> for Filter<HANDLER, PREDICATE>
+where
+    EVENT: Send,
+    HANDLER: Handler<EVENT>,
+    PREDICATE: FnMut(&EVENT) -> bool,
+{
+    type Output = HANDLER::Output;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        self.handler.start(sender);
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        if (self.predicate)(&event) {
+            self.handler.handle(event, context)
+        } else {
+            Flow::Continue
+        }
+    }
+
+    fn idle(&mut self) -> Idle {
+        self.handler.idle()
+    }
+
+    fn end(self) -> HANDLER::Output {
+        self.handler.end()
+    }
+}
+
+/// Handler which transforms every event before passing it on.
+///
+/// Created by `Map::new`, the inner handler handles events of the same type as the event loop and is
+/// started with the sender of the event loop. Created by `Map::with_unmap`, the inner handler handles
+/// events of its own type and is started with a sender of that type, whose events are converted back
+/// by `unmap`. Either way, events sent by the inner handler pass through `map` once they are handled.
+/// Use `Filter` to drop events instead. Events are passed to `Handler::handle` of the inner handler
+/// one by one.
+///
+/// ```rust
+/// use mrogalski_looper::{Builder, Context, Exit, Flow, Handler, Map, Sender};
+///
+/// struct Text(String);
+///
+/// struct Lengths(Vec<usize>);
+///
+/// impl Handler<usize> for Lengths {
+///     type Output = Vec<usize>;
+///     fn start(&mut self, sender: Sender<usize>) {
+///         sender.send(2).unwrap();
+///     }
+///     fn handle(&mut self, length: usize, _: &mut Context<usize>) -> Flow {
+///         self.0.push(length);
+///         Flow::Continue
+///     }
+///     fn end(self) -> Vec<usize> {
+///         self.0
+///     }
+/// }
+///
+/// let lengths = Map::with_unmap(
+///     Lengths(vec![]),
+///     |text: Text| text.0.len(),
+///     |length: usize| Text("x".repeat(length)),
+/// );
+/// let result = Builder::new().run_with(lengths, |_, invoker| {
+///     invoker.sender().send(Text("abc".to_string())).unwrap();
+/// });
+/// assert_eq!(result, (Exit::Disconnected, vec![3, 2]));
+/// ```
+#[derive(Debug)]
+pub struct Map<HANDLER, F, UNMAP = Same> {
+    handler: HANDLER,
+    map: F,
+    unmap: Arc<UNMAP>,
+}
+
+/// Reverse mapping of a `Map` created by `Map::new`, whose events keep their type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Same;
+
+impl<HANDLER, F> Map<HANDLER, F> {
+    /// Creates a handler which passes the events returned by `map` to `handler`.
+    ///
+    /// ```rust
+    /// use mrogalski_looper::{Context, Flow, Handler, Map, Sender, run};
+    ///
+    /// struct Collect(Vec<i32>);
+    ///
+    /// impl Handler<i32> for Collect {
+    ///     type Output = Vec<i32>;
+    ///     fn start(&mut self, sender: Sender<i32>) {
+    ///         for i in 1..4 {
+    ///             sender.send(i).unwrap();
+    ///         }
+    ///     }
+    ///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+    ///         self.0.push(i);
+    ///         Flow::Continue
+    ///     }
+    ///     fn end(self) -> Vec<i32> {
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let squares = Map::new(Collect(vec![]), |i: i32| i * i);
+    /// assert_eq!(run(squares).1, vec![1, 4, 9]);
+    /// ```
+    pub fn new(handler: HANDLER, map: F) -> Map<HANDLER, F> {
+        Map {
+            handler,
+            map,
+            unmap: Arc::new(Same),
+        }
+    }
+}
+
+impl<HANDLER, F, UNMAP> Map<HANDLER, F, UNMAP> {
+    /// Creates a handler which passes the events returned by `map` to `handler`, which sends its
+    /// events through a sender which converts them with `unmap`.
+    pub fn with_unmap(handler: HANDLER, map: F, unmap: UNMAP) -> Map<HANDLER, F, UNMAP> {
+        Map {
+            handler,
+            map,
+            unmap: Arc::new(unmap),
+        }
+    }
+}
+
+impl<EVENT, HANDLER, F> Handler<EVENT> for Map<HANDLER, F, Same>
+where
+    EVENT: Send,
+    HANDLER: Handler<EVENT>,
+    F: FnMut(EVENT) -> EVENT,
+{
+    type Output = HANDLER::Output;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        self.handler.start(sender);
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        self.handler.handle((self.map)(event), context)
+    }
+
+    fn idle(&mut self) -> Idle {
+        self.handler.idle()
+    }
+
+    fn end(self) -> HANDLER::Output {
+        self.handler.end()
+    }
+}
+
+impl<A, B, HANDLER, F, UNMAP> Handler<A> for Map<HANDLER, F, UNMAP>
+where
+    A: Send + 'static,
+    B: Send + 'static,
+    HANDLER: Handler<B>,
+    F: FnMut(A) -> B,
+    UNMAP: Fn(B) -> A + Send + Sync + 'static,
+{
+    type Output = HANDLER::Output;
+
+    fn start(&mut self, sender: Sender<A>) {
+        let unmap = self.unmap.clone();
+        self.handler
+            .start(sender.map(move |event| unmap(event)).into_sender());
+    }
+
+    fn handle(&mut self, event: A, context: &mut Context<A>) -> Flow {
+        let event = (self.map)(event);
+        let handler = &mut self.handler;
+        let unmap = self.unmap.clone();
+        context.delegate(
+            move |event| unmap(event),
+            |context| handler.handle(event, context),
+        )
+    }
+
+    fn idle(&mut self) -> Idle {
+        self.handler.idle()
+    }
+
+    fn end(self) -> HANDLER::Output {
+        self.handler.end()
+    }
+}
+
+/// Handler which passes `Either::Left` events to one inner handler and `Either::Right` events to
+/// another.
+///
+/// Each inner handler handles events of its own type and is started with a sender of that type,
+/// whose events are wrapped in the matching variant. Both produce the output together. The `Flow`
+/// returned by the handler which received an event decides whether the event loop keeps running.
+/// Use `Map` in front of the split to route events of a single type.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Either, Flow, Handler, Sender, Split, run};
+///
+/// struct Numbers(Vec<i32>);
+///
+/// impl Handler<i32> for Numbers {
+///     type Output = Vec<i32>;
+///     fn start(&mut self, sender: Sender<i32>) {
+///         for i in 0..3 {
+///             sender.send(i).unwrap();
+///         }
+///     }
+///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+///         self.0.push(i);
+///         Flow::Continue
+///     }
+///     fn end(self) -> Vec<i32> {
+///         self.0
+///     }
+/// }
+///
+/// struct Words(Vec<&'static str>);
+///
+/// impl Handler<&'static str> for Words {
+///     type Output = Vec<&'static str>;
+///     fn start(&mut self, sender: Sender<&'static str>) {
+///         sender.send("one").unwrap();
+///     }
+///     fn handle(&mut self, word: &'static str, _: &mut Context<&'static str>) -> Flow {
+///         self.0.push(word);
+///         Flow::Continue
+///     }
+///     fn end(self) -> Vec<&'static str> {
+///         self.0
+///     }
+/// }
+///
+/// let split = Split::new(Numbers(vec![]), Words(vec![]));
+/// assert_eq!(run(split).1, (vec![0, 1, 2], vec!["one"]));
+/// ```
+#[derive(Debug)]
+pub struct Split<LEFT, RIGHT> {
+    left: LEFT,
+    right: RIGHT,
+}
+
+impl<LEFT, RIGHT> Split<LEFT, RIGHT> {
+    /// Creates a handler which passes `Either::Left` events to `left` and `Either::Right` events to
+    /// `right`.
+    pub fn new(left: LEFT, right: RIGHT) -> Split<LEFT, RIGHT> {
+        Split { left, right }
+    }
+}
+
+impl<A, B, LEFT, RIGHT> Handler<Either<A, B>> for Split<LEFT, RIGHT>
+where
+    A: Send + 'static,
+    B: Send + 'static,
+    LEFT: Handler<A>,
+    RIGHT: Handler<B>,
+{
+    type Output = (LEFT::Output, RIGHT::Output);
+
+    fn start(&mut self, sender: Sender<Either<A, B>>) {
+        self.left.start(sender.map(Either::Left).into_sender());
+        self.right.start(sender.map(Either::Right).into_sender());
+    }
+
+    fn handle(&mut self, event: Either<A, B>, context: &mut Context<Either<A, B>>) -> Flow {
+        match event {
+            Either::Left(event) => {
+                let left = &mut self.left;
+                context.delegate(Either::Left, |context| left.handle(event, context))
+            }
+            Either::Right(event) => {
+                let right = &mut self.right;
+                context.delegate(Either::Right, |context| right.handle(event, context))
+            }
+        }
+    }
+
+    /// Calls `idle` of both handlers. Returns `Idle::Again` if either of them does.
+    fn idle(&mut self) -> Idle {
+        match (self.left.idle(), self.right.idle()) {
+            (Idle::Wait, Idle::Wait) => Idle::Wait,
+            _ => Idle::Again,
+        }
+    }
+
+    fn end(self) -> (LEFT::Output, RIGHT::Output) {
+        (self.left.end(), self.right.end())
+    }
+}
+
+/// Handler which replaces its first handler with a second one, created from the output of the first.
+///
+/// When the first handler returns `Flow::Stop`, its `end` is called and the output is passed to the
+/// factory of the second handler. The second handler is started right away and receives all
+/// further events. The chain produces `Either::Left` with the output of the first handler if the
+/// event loop terminates before the switch. It produces `None` if the switch was interrupted by a
+/// panic in `end` of the first handler, in the factory or in `start` of the second handler, since
+/// the first handler is gone by then.
+pub struct Chain<FIRST, SECOND, NEXT> {
+    stage: Stage<FIRST, SECOND>,
+    next: Option<NEXT>,
+}
+
+enum Stage<FIRST, SECOND> {
+    First(FIRST),
+    Second(SECOND),
+    // Left behind if switching to the second handler panics. The chain stops and ends with `None`.
+    Switching,
+}
+
+impl<FIRST, SECOND, NEXT> Chain<FIRST, SECOND, NEXT> {
+    /// Creates a handler which runs `first` and then the handler returned by `next`.
+    pub fn new(first: FIRST, next: NEXT) -> Chain<FIRST, SECOND, NEXT> {
+        Chain {
+            stage: Stage::First(first),
+            next: Some(next),
+        }
+    }
+}
+
+impl<FIRST, SECOND, NEXT> Chain<FIRST, SECOND, NEXT> {
+    /// Switches to the second handler if the first one returned `Flow::Stop`.
+    fn switch<EVENT>(&mut self, flow: Flow, context: &mut Context<EVENT>) -> Flow
+    where
+        EVENT: Send,
+        FIRST: Handler<EVENT>,
+        SECOND: Handler<EVENT>,
+        NEXT: FnOnce(FIRST::Output) -> SECOND,
+    {
+        if flow != Flow::Stop {
+            return flow;
+        }
+        if let Stage::First(first) = mem::replace(&mut self.stage, Stage::Switching) {
+            let next = self.next.take().expect("chain switched twice");
+            let mut second = next(first.end());
+            second.start(context.sender());
+            self.stage = Stage::Second(second);
+        }
+        Flow::Continue
+    }
+}
+
+impl<EVENT, FIRST, SECOND, NEXT> Handler<EVENT> for Chain<FIRST, SECOND, NEXT>
+where
+    EVENT: Send,
+    FIRST: Handler<EVENT>,
+    SECOND: Handler<EVENT>,
+    NEXT: FnOnce(FIRST::Output) -> SECOND,
+{
+    type Output = Option<Either<FIRST::Output, SECOND::Output>>;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        if let Stage::First(ref mut first) = self.stage {
+            first.start(sender);
+        }
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        let flow = match self.stage {
+            Stage::First(ref mut first) => first.handle(event, context),
+            Stage::Second(ref mut second) => return second.handle(event, context),
+            Stage::Switching => return Flow::Stop,
+        };
+        let flow = context.settle(flow);
+        self.switch(flow, context)
+    }
+
+    fn idle(&mut self) -> Idle {
+        match self.stage {
+            Stage::First(ref mut first) => first.idle(),
+            Stage::Second(ref mut second) => second.idle(),
+            Stage::Switching => Idle::Wait,
+        }
+    }
+
+    fn end(self) -> Option<Either<FIRST::Output, SECOND::Output>> {
+        match self.stage {
+            Stage::First(first) => Some(Either::Left(first.end())),
+            Stage::Second(second) => Some(Either::Right(second.end())),
+            Stage::Switching => None,
+        }
+    }
+}
+
+impl<FIRST, SECOND, NEXT> fmt::Debug for Chain<FIRST, SECOND, NEXT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self.stage {
+            Stage::First(_) => "First",
+            Stage::Second(_) => "Second",
+            Stage::Switching => "Switching",
+        };
+        f.debug_struct("Chain").field("stage", &stage).finish()
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::mailbox::{Sender, WeakSender};
+use super::Flow;
+
+/// State of the event loop passed to `Handler::handle` and `Handler::handle_batch`.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Exit, Flow, Handler, Sender, run};
+///
+/// struct Countdown;
+///
+/// impl Handler<u32> for Countdown {
+///     type Output = ();
+///     fn start(&mut self, sender: Sender<u32>) {
+///         sender.send(3).unwrap();
+///     }
+///     fn handle(&mut self, i: u32, context: &mut Context<u32>) -> Flow {
+///         if i == 0 {
+///             context.exit(7);
+///         } else {
+///             context.sender().send(i - 1).unwrap();
+///         }
+///         Flow::Continue
+///     }
+///     fn end(self) {}
+/// }
+///
+/// assert_eq!(run(Countdown).0, Exit::Code(7));
+/// ```
+pub struct Context<EVENT> {
+    sender: WeakSender<EVENT>,
+    // Number of the first event in the current batch.
+    pub(crate) seq: u64,
+    flow: Option<Flow>,
+    pub(crate) stats: Stats,
+}
+
+impl<EVENT> Context<EVENT> {
+    pub(crate) fn new(sender: &Sender<EVENT>) -> Context<EVENT> {
+        Context {
+            sender: sender.downgrade(),
+            seq: 0,
+            flow: None,
+            stats: Stats {
+                started: Instant::now(),
+                events: 0,
+                batches: 0,
+                panics: 0,
+            },
+        }
+    }
+
+    /// Returns a sender connected to this event loop.
+    ///
+    /// Unlike the sender passed to `Handler::start`, it doesn't have to be stored by the handler.
+    /// Dropping it right away doesn't terminate the event loop before the events sent through it are
+    /// handled.
+    pub fn sender(&self) -> Sender<EVENT> {
+        self.sender
+            .reconnect()
+            .expect("mailbox closed while the event loop is running")
+    }
+
+    /// Terminates the event loop once the current call returns, dropping all queued events.
+    ///
+    /// Takes effect only if the handler returns `Flow::Continue`. Otherwise the returned `Flow` wins.
+    pub fn stop(&mut self) {
+        self.flow = Some(Flow::Stop);
+    }
+
+    /// Handles the events that are already queued and then terminates the event loop.
+    ///
+    /// Takes effect only if the handler returns `Flow::Continue`.
+    pub fn drain(&mut self) {
+        self.flow = Some(Flow::Drain);
+    }
+
+    /// Terminates the event loop once the current call returns and reports the given exit code.
+    ///
+    /// Takes effect only if the handler returns `Flow::Continue`.
+    pub fn exit(&mut self, code: i32) {
+        self.flow = Some(Flow::Exit(code));
+    }
+
+    /// Sequence number of the event being handled.
+    ///
+    /// Events are numbered from zero in the order in which the event loop takes them out of the
+    /// mailbox. In `Handler::handle_batch`, this is the number of the first event in the batch.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Statistics of the event loop.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Calls `f` with a context for an inner handler of events of type `T`, whose events are
+    /// converted to `EVENT` by `map`.
+    ///
+    /// The inner context shares the sequence number and statistics of this one. Whatever the inner
+    /// handler requests through it is requested through this context.
+    pub(crate) fn delegate<T, M, F, R>(&mut self, map: M, f: F) -> R
+    where
+        EVENT: Send + 'static,
+        T: Send + 'static,
+        M: Fn(T) -> EVENT + Send + Sync + 'static,
+        F: FnOnce(&mut Context<T>) -> R,
+    {
+        let mut context = Context {
+            sender: self.sender.map(map),
+            seq: self.seq,
+            flow: self.flow.take(),
+            stats: self.stats,
+        };
+        let result = f(&mut context);
+        self.flow = context.flow;
+        result
+    }
+
+    /// Combines `flow` returned by the handler with the one requested through this context.
+    pub(crate) fn settle(&mut self, flow: Flow) -> Flow {
+        match (flow, self.flow.take()) {
+            (Flow::Continue, Some(requested)) => requested,
+            (flow, _) => flow,
+        }
+    }
+}
+
+impl<EVENT> fmt::Debug for Context<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("seq", &self.seq)
+            .field("flow", &self.flow)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+/// Counters describing the work done by an event loop so far.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    started: Instant,
+    pub(crate) events: u64,
+    pub(crate) batches: u64,
+    pub(crate) panics: u64,
+}
+
+impl Stats {
+    /// Time elapsed since the event loop started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Number of events taken out of the mailbox, including the ones in the current batch.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Number of calls to `Handler::handle_batch`, including the current one.
+    pub fn batches(&self) -> u64 {
+        self.batches
+    }
+
+    /// Number of panics which were skipped because of `PanicPolicy::Skip`.
+    pub fn panics(&self) -> u64 {
+        self.panics
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::journal::Codec;
+use super::mailbox::{Ack, SendError, Sender};
+
+const EVENTS: &str = "events.log";
+const ACKS: &str = "acks.log";
+// Locked while the queue is open, so that a single `DurableSender` owns it.
+const LOCK: &str = "lock";
+// Id and length of the encoded event.
+const HEADER: usize = 8 + 4;
+
+/// Events which were stored but not acknowledged, with their ids.
+type Unacked<EVENT> = Vec<(u64, EVENT)>;
+
+/// Sender which stores events on disk until the event loop has handled them.
+///
+/// `send` appends the event to a log in the queue directory before passing it to the event loop.
+/// Once `Handler::handle_batch` returns, the handled events are acknowledged. Events which were
+/// never acknowledged, because the process died or the event loop terminated before handling
+/// them, are sent again by `open`. This gives at-least-once delivery: an event may be handled again
+/// if the process dies right after handling it.
+///
+/// Only events sent through a `DurableSender` are stored. Events sent through plain senders, or
+/// scheduled with `Sender::send_after`, are not. Events discarded by `Overflow::DropNewest` or
+/// `Overflow::DropOldest` are acknowledged as if they were handled, so they aren't sent again.
+///
+/// ```rust
+/// use mrogalski_looper::{Codec, DurableSender, FnHandler, Flow, spawn};
+/// use std::io;
+///
+/// struct Bytes;
+///
+/// impl Codec<u8> for Bytes {
+///     fn encode(&mut self, event: &u8, buf: &mut Vec<u8>) -> io::Result<()> {
+///         buf.push(*event);
+///         Ok(())
+///     }
+///     fn decode(&mut self, bytes: &[u8]) -> io::Result<u8> {
+///         Ok(bytes[0])
+///     }
+/// }
+///
+/// let dir = std::env::temp_dir().join(format!("looper-durable-doc-{}", std::process::id()));
+/// let looper = spawn(FnHandler::new(0, |sum: &mut u32, i: u8, _| {
+///     *sum += i as u32;
+///     Flow::Continue
+/// }));
+/// let sender = DurableSender::open(&dir, Bytes, looper.sender().clone()).unwrap();
+/// sender.send(2).unwrap();
+/// sender.send(3).unwrap();
+/// drop(sender);
+/// assert_eq!(looper.join().unwrap().1, 5);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct DurableSender<EVENT, CODEC> {
+    sender: Sender<EVENT>,
+    queue: Arc<Mutex<Queue<CODEC>>>,
+}
+
+impl<EVENT, CODEC> DurableSender<EVENT, CODEC>
+where
+    CODEC: Codec<EVENT> + Send + 'static,
+{
+    /// Opens the queue stored in `dir` and connects it to the event loop of `sender`.
+    ///
+    /// The directory is created if it doesn't exist. Events left unacknowledged by a previous run
+    /// are sent to the event loop right away, in their original order. Fails with
+    /// `io::ErrorKind::WouldBlock` if the queue is already open, in this process or another one,
+    /// until all clones of that `DurableSender` and the events sent through them are dropped.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        codec: CODEC,
+        sender: Sender<EVENT>,
+    ) -> io::Result<DurableSender<EVENT, CODEC>> {
+        let (queue, unacked) = Queue::open(dir.as_ref(), codec)?;
+        let durable = DurableSender {
+            sender,
+            queue: Arc::new(Mutex::new(queue)),
+        };
+        for (id, event) in unacked {
+            let ack = durable.ack(id);
+            if durable.sender.send_acked(event, ack).is_err() {
+                return Err(io::Error::other("event loop rejected a redelivered event"));
+            }
+        }
+        Ok(durable)
+    }
+
+    /// Stores an event on disk and sends it to the event loop.
+    ///
+    /// If the event loop rejects the event, it's removed from the disk again.
+    pub fn send(&self, event: EVENT) -> Result<(), DurableError<EVENT>> {
+        let id = match self.lock().append(&event) {
+            Ok(id) => id,
+            Err(error) => return Err(DurableError::Io(error, event)),
+        };
+        self.sender
+            .send_acked(event, self.ack(id))
+            .map_err(|error| {
+                // Failing to record the removal only means that the event is sent again later.
+                let _ = self.lock().ack(id);
+                DurableError::Send(error)
+            })
+    }
+
+    /// Sender connected to the same event loop, which doesn't store events.
+    pub fn sender(&self) -> &Sender<EVENT> {
+        &self.sender
+    }
+
+    fn ack(&self, id: u64) -> Ack {
+        let queue = self.queue.clone();
+        Box::new(move || {
+            // Failing to record the acknowledgement only means that the event is sent again later.
+            let _ = lock(&queue).ack(id);
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue<CODEC>> {
+        lock(&self.queue)
+    }
+}
+
+impl<EVENT, CODEC> Clone for DurableSender<EVENT, CODEC> {
+    fn clone(&self) -> DurableSender<EVENT, CODEC> {
+        DurableSender {
+            sender: self.sender.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<EVENT, CODEC> fmt::Debug for DurableSender<EVENT, CODEC> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let queue = lock(&self.queue);
+        f.debug_struct("DurableSender")
+            .field("dir", &queue.dir)
+            .field("pending", &queue.pending)
+            .finish()
+    }
+}
+
+/// Error returned by `DurableSender::send`.
+pub enum DurableError<EVENT> {
+    /// The event loop rejected the event, which was then removed from the disk.
+    Send(SendError<EVENT>),
+    /// The event could not be stored, so it wasn't sent.
+    Io(io::Error, EVENT),
+}
+
+impl<EVENT> DurableError<EVENT> {
+    /// Returns the event that could not be sent.
+    pub fn into_inner(self) -> EVENT {
+        match self {
+            DurableError::Send(error) => error.into_inner(),
+            DurableError::Io(_, event) => event,
+        }
+    }
+}
+
+impl<EVENT> fmt::Debug for DurableError<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DurableError::Send(ref error) => write!(f, "Send({:?})", error),
+            DurableError::Io(ref error, _) => write!(f, "Io({:?}, ..)", error),
+        }
+    }
+}
+
+impl<EVENT> fmt::Display for DurableError<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DurableError::Send(ref error) => error.fmt(f),
+            DurableError::Io(ref error, _) => write!(f, "storing an event failed: {}", error),
+        }
+    }
+}
+
+impl<EVENT> Error for DurableError<EVENT> {}
+
+/// Events stored on disk, and the acknowledgements of the ones already handled.
+///
+/// Both logs are truncated whenever all stored events are acknowledged, and compacted by `open`.
+struct Queue<CODEC> {
+    codec: CODEC,
+    dir: PathBuf,
+    // Holds the lock on the queue until the queue is dropped.
+    _lock: File,
+    events: File,
+    acks: File,
+    next_id: u64,
+    // Number of stored events which were not acknowledged yet.
+    pending: usize,
+    buf: Vec<u8>,
+}
+
+impl<CODEC> Queue<CODEC> {
+    /// Opens the queue in `dir` and returns the unacknowledged events.
+    fn open<EVENT>(dir: &Path, mut codec: CODEC) -> io::Result<(Queue<CODEC>, Unacked<EVENT>)>
+    where
+        CODEC: Codec<EVENT>,
+    {
+        fs::create_dir_all(dir)?;
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "queue is already open",
+                ))
+            }
+            Err(TryLockError::Error(error)) => return Err(error),
+        }
+        let acked: HashSet<u64> = read(&dir.join(ACKS))?
+            .chunks_exact(8)
+            .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+        let log = read(&dir.join(EVENTS))?;
+        let mut compacted = vec![];
+        let mut unacked = vec![];
+        let mut next_id = 0;
+        let mut rest = &log[..];
+        while rest.len() >= HEADER {
+            let id = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[8..HEADER].try_into().unwrap()) as usize;
+            if rest.len() < HEADER + len {
+                // Cut short when the process died while appending it, so it was never sent.
+                break;
+            }
+            let (record, tail) = rest.split_at(HEADER + len);
+            if !acked.contains(&id) {
+                unacked.push((id, codec.decode(&record[HEADER..])?));
+                compacted.extend_from_slice(record);
+            }
+            next_id = id + 1;
+            rest = tail;
+        }
+
+        let tmp = dir.join("events.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&compacted)?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(EVENTS))?;
+        File::create(dir.join(ACKS))?.sync_all()?;
+
+        let append = |name| OpenOptions::new().append(true).open(dir.join(name));
+        let queue = Queue {
+            codec,
+            dir: dir.to_path_buf(),
+            _lock: lock,
+            events: append(EVENTS)?,
+            acks: append(ACKS)?,
+            next_id,
+            pending: unacked.len(),
+            buf: vec![],
+        };
+        Ok((queue, unacked))
+    }
+
+    /// Stores `event` and returns its id.
+    fn append<EVENT>(&mut self, event: &EVENT) -> io::Result<u64>
+    where
+        CODEC: Codec<EVENT>,
+    {
+        let id = self.next_id;
+        self.buf.clear();
+        self.buf.resize(HEADER, 0);
+        self.codec.encode(event, &mut self.buf)?;
+        let len = self.buf.len() - HEADER;
+        if len > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "event too large for the queue",
+            ));
+        }
+        self.buf[0..8].copy_from_slice(&id.to_le_bytes());
+        self.buf[8..HEADER].copy_from_slice(&(len as u32).to_le_bytes());
+        self.events.write_all(&self.buf)?;
+        self.events.sync_data()?;
+        self.next_id += 1;
+        self.pending += 1;
+        Ok(id)
+    }
+
+    /// Records that the event with the given id doesn't have to be sent again.
+    fn ack(&mut self, id: u64) -> io::Result<()> {
+        self.pending -= 1;
+        if self.pending == 0 {
+            // Appends go to the end of the files, so they can simply be emptied.
+            self.events.set_len(0)?;
+            self.acks.set_len(0)
+        } else {
+            self.acks.write_all(&id.to_le_bytes())
+        }
+    }
+}
+
+/// Locks the queue, even if a thread panicked while holding the lock.
+///
+/// The queue stays consistent, since it's updated only after the files are written.
+fn lock<CODEC>(queue: &Mutex<Queue<CODEC>>) -> MutexGuard<'_, Queue<CODEC>> {
+    queue.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads the whole file, treating a missing file as empty.
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    match fs::read(path) {
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        result => result,
+    }
+}
@@ -0,0 +1,180 @@
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use super::context::Context;
+use super::mailbox::{SendError, Sender};
+use super::timer::Timer;
+use super::Flow;
+
+/// Handles events of type `T` in a handler which accepts several event types.
+///
+/// Such a handler implements `Handler<Envelope<Self>>` and opens every envelope in
+/// `Handler::handle`, which calls the `Receive` implementation matching the type of the event.
+/// Producers send their events through a `TypedSender`, so no wrapper enum is needed.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Envelope, Flow, Handler, Receive, Sender, run};
+///
+/// #[derive(Default)]
+/// struct Log(Vec<String>);
+///
+/// impl Receive<i32> for Log {
+///     fn receive(&mut self, i: i32, _: &mut Context<Envelope<Log>>) -> Flow {
+///         self.0.push(format!("number {}", i));
+///         Flow::Continue
+///     }
+/// }
+///
+/// impl Receive<&'static str> for Log {
+///     fn receive(&mut self, s: &'static str, _: &mut Context<Envelope<Log>>) -> Flow {
+///         self.0.push(format!("text {}", s));
+///         Flow::Continue
+///     }
+/// }
+///
+/// impl Handler<Envelope<Log>> for Log {
+///     type Output = Vec<String>;
+///     fn start(&mut self, sender: Sender<Envelope<Log>>) {
+///         sender.typed::<i32>().send(1).unwrap();
+///         sender.typed::<&'static str>().send("two").unwrap();
+///     }
+///     fn handle(&mut self, envelope: Envelope<Log>, context: &mut Context<Envelope<Log>>) -> Flow {
+///         envelope.open(self, context)
+///     }
+///     fn end(self) -> Vec<String> {
+///         self.0
+///     }
+/// }
+///
+/// assert_eq!(run(Log::default()).1, vec!["number 1", "text two"]);
+/// ```
+pub trait Receive<T>: Sized {
+    /// Called for every event of type `T` sent to the event loop.
+    fn receive(&mut self, event: T, context: &mut Context<Envelope<Self>>) -> Flow;
+}
+
+type Deliver<HANDLER> =
+    fn(&mut HANDLER, Box<dyn Any + Send>, &mut Context<Envelope<HANDLER>>) -> Flow;
+
+/// Event of any type accepted by `HANDLER` through its `Receive` implementations.
+pub struct Envelope<HANDLER> {
+    event: Box<dyn Any + Send>,
+    deliver: Deliver<HANDLER>,
+}
+
+impl<HANDLER> Envelope<HANDLER> {
+    /// Wraps `event` so that it can be sent to the event loop of `HANDLER`.
+    pub fn new<T>(event: T) -> Envelope<HANDLER>
+    where
+        T: Send + 'static,
+        HANDLER: Receive<T>,
+    {
+        fn deliver<T: 'static, HANDLER: Receive<T>>(
+            handler: &mut HANDLER,
+            event: Box<dyn Any + Send>,
+            context: &mut Context<Envelope<HANDLER>>,
+        ) -> Flow {
+            let event = event
+                .downcast::<T>()
+                .expect("envelope with a mismatched event");
+            handler.receive(*event, context)
+        }
+        Envelope {
+            event: Box::new(event),
+            deliver: deliver::<T, HANDLER>,
+        }
+    }
+
+    /// Passes the event to the matching `Receive::receive` method of `handler`.
+    pub fn open(self, handler: &mut HANDLER, context: &mut Context<Envelope<HANDLER>>) -> Flow {
+        (self.deliver)(handler, self.event, context)
+    }
+
+    /// Returns the event if it is of type `T`, or the envelope otherwise.
+    pub fn downcast<T: 'static>(self) -> Result<T, Envelope<HANDLER>> {
+        if self.event.is::<T>() {
+            Ok(*self.event.downcast::<T>().unwrap())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<HANDLER> fmt::Debug for Envelope<HANDLER> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Envelope { .. }")
+    }
+}
+
+impl<HANDLER> Sender<Envelope<HANDLER>> {
+    /// Returns a sender of events of type `T` connected to the same event loop.
+    pub fn typed<T>(&self) -> TypedSender<T, HANDLER>
+    where
+        T: Send + 'static,
+        HANDLER: Receive<T>,
+    {
+        TypedSender {
+            sender: self.clone(),
+            event: PhantomData,
+        }
+    }
+}
+
+/// Sender of events of one of the types accepted by a handler of `Envelope`s.
+///
+/// Created by `Sender::typed`. Counts as a `Sender` of the event loop.
+pub struct TypedSender<T, HANDLER> {
+    sender: Sender<Envelope<HANDLER>>,
+    event: PhantomData<fn(T)>,
+}
+
+impl<T, HANDLER> TypedSender<T, HANDLER>
+where
+    T: Send + 'static,
+    HANDLER: Receive<T>,
+{
+    /// Sends an event to the event loop. Works like `Sender::send`.
+    pub fn send(&self, event: T) -> Result<(), SendError<T>> {
+        self.sender
+            .send(Envelope::new(event))
+            .map_err(|error| error.map(TypedSender::unwrap))
+    }
+
+    /// Delivers an event to the event loop once `delay` elapses. Works like `Sender::send_after`.
+    pub fn send_after(&self, delay: Duration, event: T) -> Result<Timer, SendError<T>> {
+        self.sender
+            .send_after(delay, Envelope::new(event))
+            .map_err(|error| error.map(TypedSender::unwrap))
+    }
+
+    /// Untyped sender connected to the same event loop.
+    pub fn sender(&self) -> &Sender<Envelope<HANDLER>> {
+        &self.sender
+    }
+
+    fn unwrap(envelope: Envelope<HANDLER>) -> T {
+        match envelope.downcast() {
+            Ok(event) => event,
+            Err(_) => unreachable!("typed sender sent an envelope of another type"),
+        }
+    }
+}
+
+impl<T, HANDLER> Clone for TypedSender<T, HANDLER> {
+    fn clone(&self) -> TypedSender<T, HANDLER> {
+        TypedSender {
+            sender: self.sender.clone(),
+            event: PhantomData,
+        }
+    }
+}
+
+impl<T, HANDLER> fmt::Debug for TypedSender<T, HANDLER> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypedSender")
+            .field("priority", &self.sender.priority())
+            .finish()
+    }
+}
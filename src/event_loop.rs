@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+
+use super::batch::Batch;
+use super::context::Context;
+use super::invoker::Jobs;
+use super::mailbox::{Ack, Message, Receiver, Request, Sender};
+use super::timer::Timers;
+use super::{Exit, Flow, Handler, Idle, Panic, PanicPolicy};
+
+/// Event taken out of the mailbox, with its acknowledgement if it was sent by a `DurableSender`.
+type Delivery<EVENT> = (EVENT, Option<Ack>);
+
+/// Settings of the event loop which don't affect its mailbox.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    pub(crate) max_batch: usize,
+    pub(crate) panic: PanicPolicy,
+}
+
+/// Runs `handler` until the event loop terminates and calls `Handler::end`.
+pub(crate) fn event_loop<EVENT: Send, HANDLER: Handler<EVENT>>(
+    mut handler: HANDLER,
+    sender: Sender<EVENT>,
+    rx: Receiver<EVENT>,
+    jobs: &Jobs<HANDLER>,
+    options: Options,
+) -> (Exit, HANDLER::Output) {
+    let exit = EventLoop::new(rx, &sender, options).run(&mut handler, sender, jobs);
+    // The mailbox is closed by now, so no more jobs can be queued.
+    jobs.clear();
+    (exit, handler.end())
+}
+
+/// State of the event loop kept between events: the mailbox, timers and unhandled events.
+pub(crate) struct EventLoop<EVENT> {
+    rx: Receiver<EVENT>,
+    timers: Timers<EVENT>,
+    batch: Batch<EVENT>,
+    // Acknowledgements of the events in `batch`, in the same order.
+    acks: VecDeque<Option<Ack>>,
+    // Number of `Message::Job` received but not run yet.
+    jobs: usize,
+    shutdown: Option<Request>,
+    context: Context<EVENT>,
+    options: Options,
+}
+
+impl<EVENT: Send> EventLoop<EVENT> {
+    pub(crate) fn new(
+        rx: Receiver<EVENT>,
+        sender: &Sender<EVENT>,
+        options: Options,
+    ) -> EventLoop<EVENT> {
+        EventLoop {
+            rx,
+            timers: Timers::new(),
+            batch: Batch::new(),
+            acks: VecDeque::new(),
+            jobs: 0,
+            shutdown: None,
+            context: Context::new(sender),
+            options,
+        }
+    }
+
+    /// Starts `handler` and passes events to it until the event loop should terminate.
+    pub(crate) fn run<HANDLER: Handler<EVENT>>(
+        &mut self,
+        handler: &mut HANDLER,
+        sender: Sender<EVENT>,
+        jobs: &Jobs<HANDLER>,
+    ) -> Exit {
+        let policy = self.options.panic;
+        let started = catch(policy, || handler.start(sender));
+        if let Err(panic) = self.count_skipped(started) {
+            return Exit::Panicked(panic);
+        }
+        // Whether any events were handled or jobs run since the last call to `Handler::idle` which
+        // didn't return `Idle::Again`.
+        let mut busy = false;
+        loop {
+            if let Some(request) = self.shutdown.take() {
+                return self.shut_down(handler, jobs, request);
+            }
+            if self.batch.is_empty() && self.jobs > 0 {
+                if let Err(panic) = self.run_jobs(handler, jobs) {
+                    return Exit::Panicked(panic);
+                }
+                busy = true;
+            }
+            if self.batch.is_empty() {
+                match self.poll() {
+                    Some((event, ack)) => self.push(event, ack),
+                    None if self.interrupted() => continue,
+                    None => {
+                        if busy {
+                            let idle = catch(policy, || handler.idle());
+                            match self.count_skipped(idle) {
+                                Ok(Some(Idle::Again)) => continue,
+                                Ok(_) => busy = false,
+                                Err(panic) => return Exit::Panicked(panic),
+                            }
+                        }
+                        match self.next() {
+                            Some((event, ack)) => self.push(event, ack),
+                            None if self.interrupted() => continue,
+                            None => return Exit::Disconnected,
+                        }
+                    }
+                }
+            }
+            self.fill(EventLoop::poll);
+            if self.shutdown.is_some() {
+                continue;
+            }
+            busy = true;
+            match self.handle_batch(handler) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Stop) => return Exit::Stopped,
+                Ok(Flow::Drain) => return self.drain(handler, jobs, Exit::Stopped, None),
+                Ok(Flow::Exit(code)) => return Exit::Code(code),
+                Err(panic) => return Exit::Panicked(panic),
+            }
+        }
+    }
+
+    /// Terminates the event loop as requested through a `Shutdown` handle.
+    fn shut_down<HANDLER: Handler<EVENT>>(
+        &mut self,
+        handler: &mut HANDLER,
+        jobs: &Jobs<HANDLER>,
+        request: Request,
+    ) -> Exit {
+        if request.drain {
+            self.drain(handler, jobs, Exit::Shutdown, request.deadline)
+        } else {
+            Exit::Shutdown
+        }
+    }
+
+    /// Handles the events and jobs that are already queued, without waiting for new ones or for
+    /// timers.
+    ///
+    /// Returns `exit` once the queue is empty or the `deadline` passes.
+    fn drain<HANDLER: Handler<EVENT>>(
+        &mut self,
+        handler: &mut HANDLER,
+        jobs: &Jobs<HANDLER>,
+        mut exit: Exit,
+        mut deadline: Option<Instant>,
+    ) -> Exit {
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return exit;
+            }
+            self.fill(EventLoop::pending);
+            if let Some(request) = self.shutdown.take() {
+                if !request.drain {
+                    return Exit::Shutdown;
+                }
+                exit = Exit::Shutdown;
+                deadline = request.deadline;
+                continue;
+            }
+            if self.batch.is_empty() {
+                if self.jobs == 0 {
+                    return exit;
+                }
+                if let Err(panic) = self.run_jobs(handler, jobs) {
+                    return Exit::Panicked(panic);
+                }
+                continue;
+            }
+            match self.handle_batch(handler) {
+                Ok(Flow::Continue) | Ok(Flow::Drain) => {}
+                Ok(Flow::Stop) => return Exit::Stopped,
+                Ok(Flow::Exit(code)) => return Exit::Code(code),
+                Err(panic) => return Exit::Panicked(panic),
+            }
+        }
+    }
+
+    /// Passes the current batch to the handler. Skipped panics count as `Flow::Continue`.
+    ///
+    /// Acknowledges the events taken out of the batch, unless the handler panicked and the event
+    /// loop terminates.
+    fn handle_batch<HANDLER: Handler<EVENT>>(
+        &mut self,
+        handler: &mut HANDLER,
+    ) -> Result<Flow, Panic> {
+        let len = self.batch.len();
+        self.context.seq = self.context.stats.events - len as u64;
+        self.context.stats.batches += 1;
+        let flow = {
+            let batch = &mut self.batch;
+            let context = &mut self.context;
+            catch(self.options.panic, || handler.handle_batch(batch, context))
+        };
+        let flow = self.count_skipped(flow)?.unwrap_or(Flow::Continue);
+        for ack in self.acks.drain(..len - self.batch.len()).flatten() {
+            ack();
+        }
+        Ok(self.context.settle(flow))
+    }
+
+    /// Runs the jobs announced so far. Skipped panics count as successfully run jobs.
+    fn run_jobs<HANDLER: Handler<EVENT>>(
+        &mut self,
+        handler: &mut HANDLER,
+        jobs: &Jobs<HANDLER>,
+    ) -> Result<(), Panic> {
+        while self.jobs > 0 {
+            self.jobs -= 1;
+            if let Some(job) = jobs.pop() {
+                let result = catch(self.options.panic, || job(handler));
+                self.count_skipped(result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a panic skipped by `catch` in the statistics.
+    fn count_skipped<R>(&mut self, result: Result<Option<R>, Panic>) -> Result<Option<R>, Panic> {
+        if let Ok(None) = result {
+            self.context.stats.panics += 1;
+        }
+        result
+    }
+
+    /// Tops up the batch with events returned by `source` until it's full, `source` runs dry or a
+    /// job is due to run.
+    fn fill(&mut self, source: fn(&mut EventLoop<EVENT>) -> Option<Delivery<EVENT>>) {
+        while self.batch.len() < self.options.max_batch && !self.interrupted() {
+            match source(self) {
+                Some((event, ack)) => self.push(event, ack),
+                None => break,
+            }
+        }
+    }
+
+    /// Returns `true` if a job or a shutdown request should be handled before further events.
+    fn interrupted(&self) -> bool {
+        self.jobs > 0 || self.shutdown.is_some()
+    }
+
+    /// Adds an event taken out of the mailbox to the batch.
+    fn push(&mut self, event: EVENT, ack: Option<Ack>) {
+        self.context.stats.events += 1;
+        self.batch.push(event);
+        self.acks.push_back(ack);
+    }
+
+    /// Returns the next queued event without blocking, after queuing the event of a timer that is
+    /// due.
+    ///
+    /// Fired timers are queued behind the messages that are already in the mailbox, so that a timer
+    /// which is always due can't hold back events, jobs or shutdown requests.
+    fn poll(&mut self) -> Option<Delivery<EVENT>> {
+        self.fire();
+        self.pending()
+    }
+
+    /// Moves the event of the earliest timer that is due to its lane in the mailbox.
+    fn fire(&mut self) {
+        if let Some((event, priority)) = self.timers.pop_due(Instant::now()) {
+            self.rx.deliver(priority, event);
+        }
+    }
+
+    /// Returns the next queued event without blocking.
+    ///
+    /// Returns `None` when it encounters a job or a shutdown request, which should be handled before
+    /// any further events.
+    fn pending(&mut self) -> Option<Delivery<EVENT>> {
+        while let Some(message) = self.rx.try_recv() {
+            match message {
+                Message::Event(event, ack) => return Some((event, ack)),
+                Message::Schedule(scheduled) => self.timers.insert(scheduled),
+                Message::Job => {
+                    self.jobs += 1;
+                    return None;
+                }
+                Message::Shutdown(request) => {
+                    self.shutdown = Some(request);
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    /// Blocks until an event is sent to the mailbox or a timer fires.
+    ///
+    /// Returns `None` once all senders are dropped and no active timers remain, or when a job or a
+    /// shutdown request is received.
+    fn next(&mut self) -> Option<Delivery<EVENT>> {
+        loop {
+            self.fire();
+            let message = match self.timers.next_deadline() {
+                Some(deadline) => match self.rx.recv(Some(deadline)) {
+                    Ok(message) => message,
+                    // Never disconnected before the deadline, since timers keep the loop alive.
+                    Err(_) => continue,
+                },
+                None => match self.rx.recv(None) {
+                    Ok(message) => message,
+                    Err(_) => return None,
+                },
+            };
+            match message {
+                Message::Event(event, ack) => return Some((event, ack)),
+                Message::Schedule(scheduled) => self.timers.insert(scheduled),
+                Message::Job => {
+                    self.jobs += 1;
+                    return None;
+                }
+                Message::Shutdown(request) => {
+                    self.shutdown = Some(request);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Calls `f`, catching any panic.
+///
+/// Returns `Ok(None)` if a panic was skipped and `Err` if it should terminate the event loop.
+pub(crate) fn catch<R, F: FnOnce() -> R>(policy: PanicPolicy, f: F) -> Result<Option<R>, Panic> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Ok(Some(result)),
+        Err(payload) => match policy {
+            PanicPolicy::Stop => Err(Panic::new(payload)),
+            PanicPolicy::Skip => Ok(None),
+        },
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+
+use super::context::Context;
+use super::mailbox::Sender;
+use super::{Flow, Handler};
+
+// Types of the default `start` and `end` closures.
+type NoStart<STATE, EVENT> = fn(&mut STATE, Sender<EVENT>);
+type Identity<STATE> = fn(STATE) -> STATE;
+
+/// Handler built from closures which share a `STATE`.
+///
+/// Useful for small event loops which don't warrant a dedicated type. By default `start` does
+/// nothing and `end` returns the state. The closures don't have to be `Send` unless the handler is
+/// passed to `spawn`.
+///
+/// ```rust
+/// use mrogalski_looper::{FnHandler, Flow, run};
+///
+/// let handler = FnHandler::new(vec![], |data: &mut Vec<i32>, i, _| {
+///     data.push(i);
+///     Flow::Continue
+/// })
+/// .on_start(|_, sender| {
+///     for i in 1..4 {
+///         sender.send(i).unwrap();
+///     }
+/// })
+/// .on_end(|data| data.iter().sum::<i32>());
+///
+/// assert_eq!(run(handler).1, 6);
+/// ```
+pub struct FnHandler<STATE, HANDLE, START, END> {
+    state: STATE,
+    handle: HANDLE,
+    // Taken when the event loop starts.
+    start: Option<START>,
+    end: END,
+}
+
+// The placeholder `()` types let `new` be called without naming the default closure types.
+impl<STATE, HANDLE> FnHandler<STATE, HANDLE, (), ()> {
+    /// Creates a handler which calls `handle` for every event.
+    pub fn new<EVENT>(
+        state: STATE,
+        handle: HANDLE,
+    ) -> FnHandler<STATE, HANDLE, NoStart<STATE, EVENT>, Identity<STATE>>
+    where
+        HANDLE: FnMut(&mut STATE, EVENT, &mut Context<EVENT>) -> Flow,
+    {
+        FnHandler {
+            state,
+            handle,
+            start: None,
+            end: |state| state,
+        }
+    }
+}
+
+impl<STATE, HANDLE, START, END> FnHandler<STATE, HANDLE, START, END> {
+    /// Calls `start` when the event loop starts.
+    pub fn on_start<EVENT, NEW>(self, start: NEW) -> FnHandler<STATE, HANDLE, NEW, END>
+    where
+        NEW: FnOnce(&mut STATE, Sender<EVENT>),
+    {
+        FnHandler {
+            state: self.state,
+            handle: self.handle,
+            start: Some(start),
+            end: self.end,
+        }
+    }
+
+    /// Produces the output of the event loop with `end`.
+    pub fn on_end<NEW, OUTPUT>(self, end: NEW) -> FnHandler<STATE, HANDLE, START, NEW>
+    where
+        NEW: FnOnce(STATE) -> OUTPUT,
+    {
+        FnHandler {
+            state: self.state,
+            handle: self.handle,
+            start: self.start,
+            end,
+        }
+    }
+}
+
+impl<STATE, EVENT, OUTPUT, HANDLE, START, END> Handler<EVENT>
+    for FnHandler<STATE, HANDLE, START, END>
+where
+    EVENT: Send,
+    HANDLE: FnMut(&mut STATE, EVENT, &mut Context<EVENT>) -> Flow,
+    START: FnOnce(&mut STATE, Sender<EVENT>),
+    END: FnOnce(STATE) -> OUTPUT,
+{
+    type Output = OUTPUT;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        if let Some(start) = self.start.take() {
+            start(&mut self.state, sender);
+        }
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        (self.handle)(&mut self.state, event, context)
+    }
+
+    fn end(self) -> OUTPUT {
+        (self.end)(self.state)
+    }
+}
+
+impl<STATE: fmt::Debug, HANDLE, START, END> fmt::Debug for FnHandler<STATE, HANDLE, START, END> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FnHandler")
+            .field("state", &self.state)
+            .finish()
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::batch::Batch;
+use super::context::Context;
+use super::mailbox::{channel, Ack, Message, Overflow, Receiver, Sender};
+use super::timer::Timers;
+use super::{Flow, Handler};
+
+/// Runs a handler step by step on the current thread, with a virtual clock for its timers.
+///
+/// Meant for tests. Events sent to the handler are queued until the test calls `step`,
+/// `run_pending` or `advance`. Timers fire only when the virtual clock is moved forward with
+/// `advance`, so timer-heavy handlers can be tested without sleeping. Jobs queued by an `Invoker`
+/// are ignored and `Handler::idle` is never called.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Flow, Handler, Harness, Sender};
+/// use std::time::Duration;
+///
+/// struct Alarm(Vec<&'static str>);
+///
+/// impl Handler<&'static str> for Alarm {
+///     type Output = ();
+///     fn start(&mut self, sender: Sender<&'static str>) {
+///         sender.send_after(Duration::from_secs(60), "ring").unwrap();
+///     }
+///     fn handle(&mut self, event: &'static str, _: &mut Context<&'static str>) -> Flow {
+///         self.0.push(event);
+///         Flow::Continue
+///     }
+///     fn end(self) {}
+/// }
+///
+/// let mut harness = Harness::new(Alarm(vec![]));
+/// harness.send("snooze");
+/// harness.advance(Duration::from_secs(59));
+/// assert_eq!(harness.handler().0, vec!["snooze"]);
+/// harness.advance(Duration::from_secs(1));
+/// assert_eq!(harness.handler().0, vec!["snooze", "ring"]);
+/// ```
+pub struct Harness<EVENT, HANDLER> {
+    handler: HANDLER,
+    sender: Sender<EVENT>,
+    rx: Receiver<EVENT>,
+    timers: Timers<EVENT>,
+    // Events left by `Handler::handle_batch`, passed to it again by the next step.
+    batch: Batch<EVENT>,
+    // Acknowledgements of the events in `batch`, in the same order.
+    acks: VecDeque<Option<Ack>>,
+    context: Context<EVENT>,
+    // Instant corresponding to the start of the virtual clock.
+    origin: Instant,
+    elapsed: Duration,
+}
+
+impl<EVENT: Send, HANDLER: Handler<EVENT>> Harness<EVENT, HANDLER> {
+    /// Starts `handler` with a sender connected to the harness.
+    pub fn new(mut handler: HANDLER) -> Harness<EVENT, HANDLER> {
+        let (sender, rx) = channel(None, Overflow::default());
+        let context = Context::new(&sender);
+        handler.start(sender.clone());
+        Harness {
+            handler,
+            sender,
+            rx,
+            timers: Timers::new(),
+            batch: Batch::new(),
+            acks: VecDeque::new(),
+            context,
+            origin: Instant::now(),
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    /// Queues an event for the handler.
+    pub fn send(&self, event: EVENT) {
+        if self.sender.send(event).is_err() {
+            unreachable!("the harness keeps its mailbox open");
+        }
+    }
+
+    /// Sender connected to the harness, which can be handed to other parts of the test.
+    pub fn sender(&self) -> &Sender<EVENT> {
+        &self.sender
+    }
+
+    /// Handles the next queued event or timer that is due.
+    ///
+    /// Like the event loop, passes the events left in the batch by `Handler::handle_batch` to it
+    /// again before taking new ones. Returns `None` if there is nothing to handle. Otherwise returns
+    /// the `Flow` with which the handler responded, taking the requests made through the `Context`
+    /// into account. A request from a `Shutdown` handle is returned as `Flow::Stop` or
+    /// `Flow::Drain`.
+    pub fn step(&mut self) -> Option<Flow> {
+        if !self.batch.is_empty() {
+            return Some(self.handle());
+        }
+        let now = self.origin + self.elapsed;
+        loop {
+            if let Some((event, priority)) = self.timers.pop_due(now) {
+                self.rx.deliver(priority, event);
+            }
+            match self.rx.try_recv()? {
+                Message::Event(event, ack) => {
+                    self.context.stats.events += 1;
+                    self.batch.push(event);
+                    self.acks.push_back(ack);
+                    return Some(self.handle());
+                }
+                Message::Schedule(mut scheduled) => {
+                    if scheduled.rebase(now) {
+                        self.timers.insert(scheduled);
+                    }
+                }
+                Message::Job => {}
+                Message::Shutdown(request) => {
+                    return Some(if request.drain {
+                        Flow::Drain
+                    } else {
+                        Flow::Stop
+                    });
+                }
+            }
+        }
+    }
+
+    /// Handles queued events and timers that are due until there are none left.
+    ///
+    /// Stops early and returns the `Flow` if the handler responds with anything other than
+    /// `Flow::Continue`.
+    pub fn run_pending(&mut self) -> Flow {
+        while let Some(flow) = self.step() {
+            if flow != Flow::Continue {
+                return flow;
+            }
+        }
+        Flow::Continue
+    }
+
+    /// Moves the virtual clock forward by `duration`, firing the timers that fall due on the way.
+    ///
+    /// Queued events are handled first. Timers fire in the order of their deadlines, with the clock
+    /// set to the deadline of each, so timers scheduled by the handler along the way fire too.
+    /// Stops early, leaving the clock where it is, if the handler responds with anything other
+    /// than `Flow::Continue`.
+    pub fn advance(&mut self, duration: Duration) -> Flow {
+        let target = self.origin + self.elapsed + duration;
+        loop {
+            let flow = self.run_pending();
+            if flow != Flow::Continue {
+                return flow;
+            }
+            match self.timers.next_deadline() {
+                Some(deadline) if deadline <= target => {
+                    self.elapsed = self.elapsed.max(deadline - self.origin);
+                }
+                _ => break,
+            }
+        }
+        self.elapsed = target - self.origin;
+        self.run_pending()
+    }
+
+    /// Time on the virtual clock since the harness was created.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The handler, for inspecting its state between steps.
+    pub fn handler(&self) -> &HANDLER {
+        &self.handler
+    }
+
+    /// The handler, for changing its state between steps.
+    pub fn handler_mut(&mut self) -> &mut HANDLER {
+        &mut self.handler
+    }
+
+    /// The context passed to the handler, for inspecting its statistics.
+    pub fn context(&self) -> &Context<EVENT> {
+        &self.context
+    }
+
+    /// Calls `Handler::end` without handling the remaining events and returns its output.
+    pub fn finish(self) -> HANDLER::Output {
+        self.handler.end()
+    }
+
+    /// Passes the batch to the handler and acknowledges the events taken out of it.
+    fn handle(&mut self) -> Flow {
+        let len = self.batch.len();
+        self.context.seq = self.context.stats.events - len as u64;
+        self.context.stats.batches += 1;
+        let flow = self
+            .handler
+            .handle_batch(&mut self.batch, &mut self.context);
+        for ack in self.acks.drain(..len - self.batch.len()).flatten() {
+            ack();
+        }
+        self.context.settle(flow)
+    }
+}
+
+impl<EVENT, HANDLER> fmt::Debug for Harness<EVENT, HANDLER> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Harness")
+            .field("elapsed", &self.elapsed)
+            .field("context", &self.context)
+            .finish()
+    }
+}
+
+/// Records the events sent to it, so that tests can check what a handler sent elsewhere.
+///
+/// Events scheduled for later delivery are recorded right away.
+///
+/// ```rust
+/// use mrogalski_looper::Probe;
+///
+/// let probe = Probe::new();
+/// probe.sender().send(1).unwrap();
+/// probe.sender().clone().send(2).unwrap();
+/// assert_eq!(probe.take(), vec![1, 2]);
+/// assert_eq!(probe.take(), vec![]);
+/// ```
+pub struct Probe<EVENT> {
+    sender: Sender<EVENT>,
+    rx: Receiver<EVENT>,
+}
+
+impl<EVENT> Probe<EVENT> {
+    /// Creates a probe with an unbounded mailbox.
+    pub fn new() -> Probe<EVENT> {
+        let (sender, rx) = channel(None, Overflow::default());
+        Probe { sender, rx }
+    }
+
+    /// Sender whose events are recorded by this probe.
+    pub fn sender(&self) -> &Sender<EVENT> {
+        &self.sender
+    }
+
+    /// Returns the events recorded since the last call, in the order in which they were sent.
+    pub fn take(&self) -> Vec<EVENT> {
+        let mut events = vec![];
+        while let Some(message) = self.rx.try_recv() {
+            match message {
+                Message::Event(event, _) => events.push(event),
+                Message::Schedule(scheduled) => events.push(scheduled.into_event()),
+                Message::Job | Message::Shutdown(_) => {}
+            }
+        }
+        events
+    }
+}
+
+impl<EVENT> Default for Probe<EVENT> {
+    fn default() -> Probe<EVENT> {
+        Probe::new()
+    }
+}
+
+impl<EVENT> fmt::Debug for Probe<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Probe { .. }")
+    }
+}
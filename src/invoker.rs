@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::call::{self, CallError, Pending};
+use super::mailbox::Sender;
+
+type Job<HANDLER> = Box<dyn FnOnce(&mut HANDLER) + Send>;
+
+/// Runs closures with access to the handler on the thread of the event loop.
+///
+/// Jobs are run between events, in the order in which they were invoked. Like a `Sender`, an
+/// `Invoker` keeps the event loop alive. Obtained from `JoinHandle::invoker` or, for an event loop
+/// running on the current thread, from `Builder::run_with`.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Flow, Handler, Sender, spawn};
+///
+/// struct Sum(i32);
+///
+/// impl Handler<i32> for Sum {
+///     type Output = ();
+///     fn start(&mut self, _: Sender<i32>) {}
+///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+///         self.0 += i;
+///         Flow::Continue
+///     }
+///     fn end(self) {}
+/// }
+///
+/// let looper = spawn(Sum(0));
+/// looper.sender().send(2).unwrap();
+/// looper.sender().send(3).unwrap();
+/// assert_eq!(looper.invoker().call(|sum| sum.0), Ok(5));
+/// looper.join().unwrap();
+/// ```
+pub struct Invoker<EVENT, HANDLER> {
+    sender: Sender<EVENT>,
+    jobs: Arc<Jobs<HANDLER>>,
+}
+
+impl<EVENT, HANDLER> Invoker<EVENT, HANDLER> {
+    pub(crate) fn new(sender: Sender<EVENT>, jobs: Arc<Jobs<HANDLER>>) -> Invoker<EVENT, HANDLER> {
+        Invoker { sender, jobs }
+    }
+
+    /// Queues `job` to be run by the event loop without waiting for it.
+    ///
+    /// The value returned by `job` can be obtained from the returned `Pending`, which can also be
+    /// dropped. If the event loop terminates before running the job, the job is dropped and the
+    /// `Pending` fails with `CallError::NoReply`.
+    pub fn invoke<T, F>(&self, job: F) -> Result<Pending<T>, CallError>
+    where
+        F: FnOnce(&mut HANDLER) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (reply, pending) = call::reply();
+        let mut queue = self.jobs.lock();
+        queue.push_back(Box::new(move |handler| reply.send(job(handler))));
+        match self.sender.send_job() {
+            Ok(()) => Ok(pending),
+            Err(_) => {
+                let job = queue.pop_back();
+                drop(queue);
+                drop(job);
+                Err(CallError::Disconnected)
+            }
+        }
+    }
+
+    /// Runs `job` on the event loop and blocks until it returns.
+    ///
+    /// Must not be called from the thread of the event loop, which would wait for itself.
+    pub fn call<T, F>(&self, job: F) -> Result<T, CallError>
+    where
+        F: FnOnce(&mut HANDLER) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.invoke(job)?.wait()
+    }
+
+    /// Sender connected to the same event loop.
+    pub fn sender(&self) -> &Sender<EVENT> {
+        &self.sender
+    }
+}
+
+impl<EVENT, HANDLER> Clone for Invoker<EVENT, HANDLER> {
+    fn clone(&self) -> Invoker<EVENT, HANDLER> {
+        Invoker {
+            sender: self.sender.clone(),
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<EVENT, HANDLER> fmt::Debug for Invoker<EVENT, HANDLER> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Invoker")
+            .field("jobs", &self.jobs.lock().len())
+            .finish()
+    }
+}
+
+/// Jobs queued by the invokers of an event loop.
+///
+/// Each job is accompanied by a `Message::Job` in the mailbox, which tells the event loop when to
+/// run it.
+pub(crate) struct Jobs<HANDLER> {
+    queue: Mutex<VecDeque<Job<HANDLER>>>,
+}
+
+impl<HANDLER> Jobs<HANDLER> {
+    pub(crate) fn new() -> Arc<Jobs<HANDLER>> {
+        Arc::new(Jobs {
+            queue: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Removes the oldest job.
+    pub(crate) fn pop(&self) -> Option<Job<HANDLER>> {
+        self.lock().pop_front()
+    }
+
+    /// Drops all queued jobs. Called once the event loop has terminated.
+    pub(crate) fn clear(&self) {
+        let queue = mem::take(&mut *self.lock());
+        // Jobs may own invokers of this event loop so they are dropped without holding the lock.
+        drop(queue);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Job<HANDLER>>> {
+        self.queue.lock().unwrap()
+    }
+}
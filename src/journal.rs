@@ -0,0 +1,629 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec;
+
+use super::context::Context;
+use super::event_loop::catch;
+use super::mailbox::{channel, Overflow, Sender};
+use super::{Flow, Handler, Idle, PanicPolicy};
+
+// Every segment starts with `MAGIC` followed by the version of its format.
+const MAGIC: &[u8] = b"LOOPERJ";
+const VERSION: u8 = 1;
+const SEGMENT_HEADER: usize = 7 + 1;
+// Sequence number, timestamp in nanoseconds, length of the kind and length of the encoded event.
+// Followed by the kind and the encoded event.
+const HEADER: usize = 8 + 8 + 1 + 4;
+const SEGMENT: &str = "journal";
+
+/// Serialization of the events stored in a journal.
+pub trait Codec<EVENT> {
+    /// Appends the bytes representing `event` to `buf`.
+    fn encode(&mut self, event: &EVENT, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Reconstructs an event from the bytes produced by `encode`.
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<EVENT>;
+
+    /// Name of the kind of `event`, such as the name of its enum variant.
+    ///
+    /// Stored next to the event in a `Journal`, so that tools can tell events apart without
+    /// decoding them. Truncated to 255 bytes. The default implementation returns an empty name.
+    fn kind(&mut self, event: &EVENT) -> String {
+        let _ = event;
+        String::new()
+    }
+}
+
+/// Event recorded in a journal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record<EVENT> {
+    /// Sequence number of the event in the journal.
+    ///
+    /// Events are numbered from zero in the order in which they were recorded, across all the runs
+    /// which appended to the journal.
+    pub seq: u64,
+    /// Kind of the event, as returned by `Codec::kind`.
+    pub kind: String,
+    /// Time at which the event was handled.
+    pub time: SystemTime,
+    /// The event itself.
+    pub event: EVENT,
+}
+
+/// Handler which appends every event to a journal before passing it on.
+///
+/// The journal is a directory of segment files. A new segment is started once the current one
+/// reaches the size set with `segment_size`. Every segment starts with the version of its format,
+/// which is checked before the segment is read. Events are passed to `Handler::handle` of the
+/// inner handler one by one, and can be fed to another handler later with `Replay`.
+///
+/// If an event can't be recorded, the event loop stops without handling it and the output is the
+/// error.
+///
+/// ```rust
+/// use mrogalski_looper::{Codec, Context, Flow, Handler, Journal, Replay, Sender, run};
+/// use std::io;
+///
+/// struct Bytes;
+///
+/// impl Codec<u8> for Bytes {
+///     fn encode(&mut self, event: &u8, buf: &mut Vec<u8>) -> io::Result<()> {
+///         buf.push(*event);
+///         Ok(())
+///     }
+///     fn decode(&mut self, bytes: &[u8]) -> io::Result<u8> {
+///         Ok(bytes[0])
+///     }
+/// }
+///
+/// struct Sum(u32);
+///
+/// impl Handler<u8> for Sum {
+///     type Output = u32;
+///     fn start(&mut self, sender: Sender<u8>) {
+///         for i in 1..4 {
+///             sender.send(i).unwrap();
+///         }
+///     }
+///     fn handle(&mut self, i: u8, _: &mut Context<u8>) -> Flow {
+///         self.0 += i as u32;
+///         Flow::Continue
+///     }
+///     fn end(self) -> u32 {
+///         self.0
+///     }
+/// }
+///
+/// let dir = std::env::temp_dir().join(format!("looper-journal-doc-{}", std::process::id()));
+/// let journal = Journal::new(Sum(0), Bytes, &dir).unwrap();
+/// assert_eq!(run(journal).1.unwrap(), 6);
+///
+/// // `Sum::start` sends more events, which are discarded during a replay.
+/// let replay = Replay::new(&dir, Bytes).unwrap();
+/// assert_eq!(replay.run(Sum(0)).unwrap(), 6);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct Journal<HANDLER, CODEC> {
+    handler: HANDLER,
+    codec: CODEC,
+    dir: PathBuf,
+    segment: Option<File>,
+    segment_len: u64,
+    segment_size: u64,
+    next_segment: u64,
+    // Sequence number of the next recorded event.
+    next_seq: u64,
+    buf: Vec<u8>,
+    error: Option<io::Error>,
+}
+
+impl<HANDLER, CODEC> Journal<HANDLER, CODEC> {
+    /// Creates a handler which records the events of `handler` in `dir`.
+    ///
+    /// The directory is created if it doesn't exist. Segments which are already there are kept,
+    /// and the new events are appended after them, numbered after the last recorded event. A
+    /// record cut short at the end of the last segment is removed first.
+    pub fn new<P: AsRef<Path>>(
+        handler: HANDLER,
+        codec: CODEC,
+        dir: P,
+    ) -> io::Result<Journal<HANDLER, CODEC>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut segments = journal_segments(&dir)?;
+        if let Some((_, path)) = segments.last() {
+            if !repair(path)? {
+                segments.pop();
+            }
+        }
+        let next_segment = segments.last().map_or(0, |&(index, _)| index + 1);
+        let next_seq = last_seq(&segments)?.map_or(0, |seq| seq + 1);
+        Ok(Journal {
+            handler,
+            codec,
+            dir,
+            segment: None,
+            segment_len: 0,
+            segment_size: 64 * 1024 * 1024,
+            next_segment,
+            next_seq,
+            buf: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// Starts a new segment once the current one would exceed `bytes`. Defaults to 64 MiB.
+    ///
+    /// Every segment holds at least one event, so segments with large events may be bigger.
+    pub fn segment_size(mut self, bytes: u64) -> Journal<HANDLER, CODEC> {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Sequence number of the next recorded event.
+    pub(crate) fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Removes the segments which hold only events numbered below `seq`.
+    ///
+    /// The last segment is always kept, since new events are appended to it.
+    pub(crate) fn compact(&self, seq: u64) -> io::Result<()> {
+        let segments = journal_segments(&self.dir)?;
+        for (i, pair) in segments.windows(2).enumerate() {
+            // Numbers grow from segment to segment, so the first event of the next segment bounds
+            // the events of this one.
+            match first_seq(&pair[1].1, i + 2 == segments.len())? {
+                Some(next) if next <= seq => fs::remove_file(&pair[0].1)?,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn handler(&self) -> &HANDLER {
+        &self.handler
+    }
+
+    pub(crate) fn handler_mut(&mut self) -> &mut HANDLER {
+        &mut self.handler
+    }
+
+    /// Makes sure that the recorded events are stored durably.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        match self.segment {
+            Some(ref segment) => segment.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends `event` to the current segment, starting a new one if necessary.
+    pub(crate) fn record<EVENT>(&mut self, event: &EVENT, seq: u64) -> io::Result<()>
+    where
+        CODEC: Codec<EVENT>,
+    {
+        let mut kind = self.codec.kind(event);
+        while kind.len() > u8::MAX as usize {
+            kind.pop();
+        }
+        self.buf.clear();
+        self.buf.resize(HEADER, 0);
+        self.buf.extend_from_slice(kind.as_bytes());
+        self.codec.encode(event, &mut self.buf)?;
+        let len = self.buf.len() - HEADER - kind.len();
+        if len > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "event too large for the journal",
+            ));
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        self.buf[0..8].copy_from_slice(&seq.to_le_bytes());
+        self.buf[8..16].copy_from_slice(&time.to_le_bytes());
+        self.buf[16] = kind.len() as u8;
+        self.buf[17..HEADER].copy_from_slice(&(len as u32).to_le_bytes());
+
+        let size = self.buf.len() as u64;
+        if self.segment_len > SEGMENT_HEADER as u64 && self.segment_len + size > self.segment_size {
+            self.segment = None;
+        }
+        if self.segment.is_none() {
+            let path = self.dir.join(numbered_name(self.next_segment, SEGMENT));
+            let mut segment = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(path)?;
+            segment.write_all(MAGIC)?;
+            segment.write_all(&[VERSION])?;
+            self.segment = Some(segment);
+            self.segment_len = SEGMENT_HEADER as u64;
+            self.next_segment += 1;
+        }
+        self.segment.as_mut().unwrap().write_all(&self.buf)?;
+        self.segment_len += size;
+        self.next_seq = seq + 1;
+        Ok(())
+    }
+}
+
+impl<EVENT, HANDLER, CODEC> Handler<EVENT> for Journal<HANDLER, CODEC>
+where
+    EVENT: Send,
+    HANDLER: Handler<EVENT>,
+    CODEC: Codec<EVENT>,
+{
+    type Output = io::Result<HANDLER::Output>;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        self.handler.start(sender);
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        let seq = self.next_seq;
+        if let Err(error) = self.record(&event, seq) {
+            self.error = Some(error);
+            return Flow::Stop;
+        }
+        self.handler.handle(event, context)
+    }
+
+    fn idle(&mut self) -> Idle {
+        self.handler.idle()
+    }
+
+    fn end(self) -> io::Result<HANDLER::Output> {
+        let output = self.handler.end();
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(output),
+        }
+    }
+}
+
+impl<HANDLER, CODEC> fmt::Debug for Journal<HANDLER, CODEC> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("dir", &self.dir)
+            .field("segment", &self.next_segment.checked_sub(1))
+            .field("segment_len", &self.segment_len)
+            .field("segment_size", &self.segment_size)
+            .finish()
+    }
+}
+
+/// Reads the events recorded by a `Journal`, in the order in which they were handled.
+///
+/// Iterates over the records of all segments. A record cut short at the end of the last segment,
+/// which is left behind when the process dies while writing it, is skipped. Anything else which
+/// doesn't fit the format of the journal is reported as `io::ErrorKind::InvalidData`.
+pub struct Replay<EVENT, CODEC> {
+    codec: CODEC,
+    segments: vec::IntoIter<PathBuf>,
+    reader: Option<SegmentReader>,
+    // Records numbered below this are passed over without decoding them.
+    from: u64,
+    event: PhantomData<fn() -> EVENT>,
+}
+
+impl<EVENT, CODEC: Codec<EVENT>> Replay<EVENT, CODEC> {
+    /// Opens the journal stored in `dir`.
+    pub fn new<P: AsRef<Path>>(dir: P, codec: CODEC) -> io::Result<Replay<EVENT, CODEC>> {
+        let segments: Vec<PathBuf> = journal_segments(dir)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect();
+        Ok(Replay {
+            codec,
+            segments: segments.into_iter(),
+            reader: None,
+            from: 0,
+            event: PhantomData,
+        })
+    }
+
+    /// Opens a single segment of a journal.
+    ///
+    /// The segment is read like the last segment of a journal, which may still be written to. Once
+    /// its records run out, the iterator returns the records appended to the segment since.
+    pub fn segment<P: AsRef<Path>>(path: P, codec: CODEC) -> io::Result<Replay<EVENT, CODEC>> {
+        let path = path.as_ref().to_path_buf();
+        fs::metadata(&path)?;
+        Ok(Replay {
+            codec,
+            segments: vec![path].into_iter(),
+            reader: None,
+            from: 0,
+            event: PhantomData,
+        })
+    }
+
+    /// Passes every recorded event to `handler` and returns its output.
+    ///
+    /// The handler is started with a sender whose events are discarded, since the events it sent
+    /// originally are already in the journal. `Context::seq` returns the recorded sequence numbers.
+    /// The `Flow` returned by the handler is ignored and `Handler::idle` is never called, so that
+    /// all recorded events are replayed.
+    pub fn run<HANDLER>(mut self, mut handler: HANDLER) -> io::Result<HANDLER::Output>
+    where
+        EVENT: Send,
+        HANDLER: Handler<EVENT>,
+    {
+        let (sender, _rx) = channel(None, Overflow::default());
+        let mut context = Context::new(&sender);
+        handler.start(sender);
+        self.feed(&mut handler, &mut context, PanicPolicy::Stop)?;
+        Ok(handler.end())
+    }
+
+    /// Skips the records numbered below `seq`, without decoding them.
+    pub(crate) fn from(mut self, seq: u64) -> Replay<EVENT, CODEC> {
+        self.from = seq;
+        self
+    }
+
+    /// Passes the remaining events to `handler`.
+    ///
+    /// With `PanicPolicy::Skip`, an event whose handling panics is skipped and counted in the
+    /// statistics. Otherwise the panic is passed on.
+    pub(crate) fn feed<HANDLER>(
+        &mut self,
+        handler: &mut HANDLER,
+        context: &mut Context<EVENT>,
+        policy: PanicPolicy,
+    ) -> io::Result<()>
+    where
+        EVENT: Send,
+        HANDLER: Handler<EVENT>,
+    {
+        while let Some(record) = self.read()? {
+            context.seq = record.seq;
+            context.stats.events += 1;
+            context.stats.batches += 1;
+            let flow = match catch(policy, || handler.handle(record.event, context)) {
+                Ok(Some(flow)) => flow,
+                Ok(None) => {
+                    context.stats.panics += 1;
+                    Flow::Continue
+                }
+                Err(panic) => panic::resume_unwind(panic.into_payload()),
+            };
+            context.settle(flow);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn into_codec(self) -> CODEC {
+        self.codec
+    }
+
+    /// Reads the next record. Returns `None` at the end of the journal.
+    fn read(&mut self) -> io::Result<Option<Record<EVENT>>> {
+        let mut header = [0; HEADER];
+        let mut bytes = vec![];
+        loop {
+            let reader = match self.reader {
+                Some(ref mut reader) => reader,
+                None => match self.segments.next() {
+                    Some(path) => {
+                        let last = self.segments.len() == 0;
+                        self.reader.get_or_insert(SegmentReader::open(&path, last)?)
+                    }
+                    None => return Ok(None),
+                },
+            };
+            if !reader.read(&mut header, &mut bytes)? {
+                if self.segments.len() == 0 {
+                    // Kept, so that records appended to the last segment can be read later.
+                    return Ok(None);
+                }
+                self.reader = None;
+            } else if u64::from_le_bytes(header[0..8].try_into().unwrap()) >= self.from {
+                break;
+            }
+        }
+        let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let time = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let (kind, event) = bytes.split_at(header[16] as usize);
+        Ok(Some(Record {
+            seq,
+            kind: String::from_utf8_lossy(kind).into_owned(),
+            time: UNIX_EPOCH + Duration::from_nanos(time),
+            event: self.codec.decode(event)?,
+        }))
+    }
+}
+
+impl<EVENT, CODEC: Codec<EVENT>> Iterator for Replay<EVENT, CODEC> {
+    type Item = io::Result<Record<EVENT>>;
+
+    fn next(&mut self) -> Option<io::Result<Record<EVENT>>> {
+        self.read().transpose()
+    }
+}
+
+impl<EVENT, CODEC> fmt::Debug for Replay<EVENT, CODEC> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("segments", &self.segments.as_slice())
+            .finish()
+    }
+}
+
+/// Name of a file with the given number and extension, which sorts in numerical order.
+pub(crate) fn numbered_name(number: u64, extension: &str) -> String {
+    format!("{:020}.{}", number, extension)
+}
+
+/// Lists the files in `dir` named by `numbered_name` with the given extension, ordered by number.
+pub(crate) fn numbered(dir: &Path, extension: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+            continue;
+        }
+        let index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse().ok());
+        if let Some(index) = index {
+            files.push((index, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Lists the segment files of the journal stored in `dir`, with their numbers, in the order in
+/// which they were written.
+pub fn journal_segments<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(u64, PathBuf)>> {
+    numbered(dir.as_ref(), SEGMENT)
+}
+
+/// Reads the records of a single segment.
+struct SegmentReader {
+    reader: BufReader<File>,
+    // Offset of the next record, or zero before the segment header is read.
+    offset: u64,
+    // Length of the file when it was last checked.
+    len: u64,
+    // Whether this is the last segment of the journal, which may end with a record cut short.
+    last: bool,
+}
+
+impl SegmentReader {
+    fn open(path: &Path, last: bool) -> io::Result<SegmentReader> {
+        let file = File::open(path)?;
+        Ok(SegmentReader {
+            len: file.metadata()?.len(),
+            reader: BufReader::new(file),
+            offset: 0,
+            last,
+        })
+    }
+
+    /// Reads the header and the bytes of the next record.
+    ///
+    /// Returns `false` at the end of the segment, including when the last segment ends with a
+    /// record cut short. The reader is then left at the start of that record, so that it can be
+    /// read once the rest of it is written.
+    fn read(&mut self, header: &mut [u8; HEADER], bytes: &mut Vec<u8>) -> io::Result<bool> {
+        if self.offset == 0 {
+            if !self.fits(SEGMENT_HEADER as u64)? {
+                return self.cut_short();
+            }
+            let mut magic = [0; SEGMENT_HEADER];
+            self.reader.read_exact(&mut magic)?;
+            if &magic[..MAGIC.len()] != MAGIC {
+                return Err(invalid("not a journal segment".to_string()));
+            }
+            if magic[MAGIC.len()] != VERSION {
+                let version = magic[MAGIC.len()];
+                return Err(invalid(format!(
+                    "unsupported journal format version {}",
+                    version
+                )));
+            }
+            self.offset = SEGMENT_HEADER as u64;
+        }
+        if !self.fits(HEADER as u64)? {
+            return self.cut_short();
+        }
+        self.reader.read_exact(header)?;
+        let len =
+            header[16] as u64 + u32::from_le_bytes(header[17..HEADER].try_into().unwrap()) as u64;
+        if !self.fits(HEADER as u64 + len)? {
+            return self.cut_short();
+        }
+        bytes.resize(len as usize, 0);
+        self.reader.read_exact(bytes)?;
+        self.offset += HEADER as u64 + len;
+        Ok(true)
+    }
+
+    /// Returns `true` if the file holds `bytes` after the start of the next record.
+    fn fits(&mut self, bytes: u64) -> io::Result<bool> {
+        if self.len < self.offset + bytes {
+            // The last segment may have grown since its length was checked.
+            self.len = self.reader.get_ref().metadata()?.len();
+        }
+        Ok(self.len >= self.offset + bytes)
+    }
+
+    /// Ends the segment at the next record, which doesn't fit in the rest of the file.
+    ///
+    /// Only the last segment may end this way, since the other ones are never written to again.
+    fn cut_short(&mut self) -> io::Result<bool> {
+        if self.len == self.offset {
+            return Ok(false);
+        }
+        if !self.last {
+            return Err(invalid(
+                "journal segment ends with a record cut short".to_string(),
+            ));
+        }
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        Ok(false)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Removes a record cut short at the end of the segment at `path`, left behind when the process
+/// died while writing it, so that new segments can follow it.
+///
+/// Returns `false` if the segment was removed, because not even its header was complete.
+fn repair(path: &Path) -> io::Result<bool> {
+    let mut reader = SegmentReader::open(path, true)?;
+    let mut header = [0; HEADER];
+    let mut bytes = vec![];
+    while reader.read(&mut header, &mut bytes)? {}
+    if reader.offset == 0 {
+        fs::remove_file(path)?;
+        return Ok(false);
+    }
+    if reader.offset < reader.len {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(reader.offset)?;
+    }
+    Ok(true)
+}
+
+/// Returns the sequence number of the first complete record in the segment at `path`.
+fn first_seq(path: &Path, last: bool) -> io::Result<Option<u64>> {
+    let mut header = [0; HEADER];
+    if SegmentReader::open(path, last)?.read(&mut header, &mut vec![])? {
+        Ok(Some(u64::from_le_bytes(header[0..8].try_into().unwrap())))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the sequence number of the last complete record in `segments`, if there is one.
+fn last_seq(segments: &[(u64, PathBuf)]) -> io::Result<Option<u64>> {
+    let mut header = [0; HEADER];
+    let mut bytes = vec![];
+    for (i, (_, path)) in segments.iter().rev().enumerate() {
+        let mut reader = SegmentReader::open(path, i == 0)?;
+        let mut last = None;
+        while reader.read(&mut header, &mut bytes)? {
+            last = Some(u64::from_le_bytes(header[0..8].try_into().unwrap()));
+        }
+        if last.is_some() {
+            return Ok(last);
+        }
+    }
+    Ok(None)
+}
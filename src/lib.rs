@@ -1,15 +1,17 @@
-//! Clean abstraction for a single-threaded event loop. Built as a lightweight wrapper around the `std::sync::mpsc` package.
+//! Clean abstraction for a single-threaded event loop. Built around a lightweight mailbox modelled after the `std::sync::mpsc` package.
 //!
 //! # Example usage:
 //!
 //! ```rust
-//! use mrogalski_looper::{Handler, Sender, run};
+//! use mrogalski_looper::{Context, Exit, Flow, Handler, Sender, run};
 //!
 //! struct ExampleHandler {
 //!     data: Vec<i32>,
 //! }
 //!
 //! impl Handler<i32> for ExampleHandler {
+//!     // Value produced by the handler once the event loop terminates.
+//!     type Output = Vec<i32>;
 //!
 //!     // Invoked right after the `run` function is called.
 //!     fn start(&mut self, sender: Sender<i32>) {
@@ -18,57 +20,327 @@
 //!         }
 //!     }
 //!
-//!     // Called for every `event` sent to the `sender`.
-//!     fn handle(&mut self, i: i32) -> bool {
+//!     // Called for every `event` sent to the `sender`. The returned `Flow` tells the event loop
+//!     // whether to keep running.
+//!     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
 //!         self.data.push(i);
-//!         true
+//!         Flow::Continue
 //!     }
 //!
-//!     // Called after last event is processed or an error occurs.
-//!     fn end(self) {
-//!         assert_eq!(self.data, vec![1, 2, 3]);
+//!     // Called after last event is processed or an error occurs. The returned value is passed
+//!     // back to the caller of `run`.
+//!     fn end(self) -> Vec<i32> {
+//!         self.data
 //!     }
 //! }
 //!
 //! // Blocks the current thread until all events are processed.
-//! run(ExampleHandler { data: vec![] });
+//! let (exit, data) = run(ExampleHandler { data: vec![] });
+//! assert_eq!(exit, Exit::Disconnected);
+//! assert_eq!(data, vec![1, 2, 3]);
 //!
 //! ```
+//!
+//! # Timers
+//!
+//! Events can also be delivered after a delay or periodically. `Sender::send_after` and
+//! `Sender::send_every` return a `Timer` handle which can be used to cancel the delivery. Timers are
+//! kept by the event loop itself so no helper threads are spawned.
+//!
+//! ```rust
+//! use mrogalski_looper::{Context, Flow, Handler, Sender, Timer, run};
+//! use std::time::Duration;
+//!
+//! struct Ticker {
+//!     ticks: u32,
+//!     timer: Option<Timer>,
+//! }
+//!
+//! impl Handler<&'static str> for Ticker {
+//!     type Output = u32;
+//!
+//!     fn start(&mut self, sender: Sender<&'static str>) {
+//!         self.timer = Some(sender.send_every(Duration::from_millis(1), "tick").unwrap());
+//!     }
+//!
+//!     fn handle(&mut self, _: &'static str, _: &mut Context<&'static str>) -> Flow {
+//!         self.ticks += 1;
+//!         if self.ticks == 3 {
+//!             // Cancelled timers don't keep the event loop alive.
+//!             self.timer.take().unwrap().cancel();
+//!         }
+//!         Flow::Continue
+//!     }
+//!
+//!     fn end(self) -> u32 {
+//!         self.ticks
+//!     }
+//! }
+//!
+//! assert_eq!(run(Ticker { ticks: 0, timer: None }).1, 3);
+//! ```
+//!
+//! # Calls
+//!
+//! `Sender::call` sends a request and blocks until the handler responds to it. The request carries
+//! a `Reply` through which the handler sends the response back. `Sender::ask` doesn't block and
+//! returns a `Pending` response instead.
+//!
+//! ```rust
+//! use mrogalski_looper::{Context, Flow, Handler, Reply, Sender, spawn};
+//!
+//! struct Counter(u32);
+//!
+//! impl Handler<Reply<u32>> for Counter {
+//!     type Output = ();
+//!
+//!     fn start(&mut self, _: Sender<Reply<u32>>) {}
+//!
+//!     fn handle(&mut self, reply: Reply<u32>, _: &mut Context<Reply<u32>>) -> Flow {
+//!         self.0 += 1;
+//!         reply.send(self.0);
+//!         Flow::Continue
+//!     }
+//!
+//!     fn end(self) {}
+//! }
+//!
+//! let looper = spawn(Counter(0));
+//! assert_eq!(looper.sender().call(|reply| reply), Ok(1));
+//! assert_eq!(looper.sender().ask(|reply| reply).unwrap().wait(), Ok(2));
+//! looper.join().unwrap();
+//! ```
+//!
+//! # Derive
+//!
+//! With the `derive` feature enabled, `#[derive(Dispatch)]` on an event enum generates a trait
+//! with one method per variant and implements `Handler` for the types which implement it. See
+//! `Dispatch` for details.
+//!
+//! # Testing
+//!
+//! `Harness` runs a handler on the test thread, one event at a time, and fires its timers from a
+//! virtual clock. `Probe` records the events that the handler sends to other event loops.
+
+#[cfg(feature = "derive")]
+extern crate mrogalski_looper_derive;
 
-#[doc(no_inline)]
-pub use std::sync::mpsc::Sender;
+pub use batch::Batch;
+pub use builder::{Builder, JoinHandle};
+pub use call::{CallError, Pending, Reply};
+pub use combinators::{Chain, Either, Filter, Map, Same, Split};
+pub use context::{Context, Stats};
+pub use durable::{DurableError, DurableSender};
+pub use envelope::{Envelope, Receive, TypedSender};
+pub use fn_handler::FnHandler;
+pub use harness::{Harness, Probe};
+pub use invoker::Invoker;
+pub use journal::{journal_segments, Codec, Journal, Record, Replay};
+pub use mailbox::{Overflow, Priority, SendError, Sender, Shutdown, WeakSender};
+pub use mapped::{MappedSender, WeakMappedSender};
+#[cfg(feature = "derive")]
+pub use mrogalski_looper_derive::Dispatch;
+pub use sourced::Sourced;
+pub use supervisor::{Restart, Supervisor};
+pub use timer::Timer;
 
+use std::any::Any;
+use std::fmt;
+
+mod batch;
+mod builder;
+mod call;
+mod combinators;
+mod context;
+mod durable;
+mod envelope;
+mod event_loop;
+mod fn_handler;
+mod harness;
+mod invoker;
+mod journal;
+mod mailbox;
+mod mapped;
+mod sourced;
+mod supervisor;
 #[cfg(test)]
 mod tests;
+mod timer;
+
+/// Tells the event loop what to do after an event has been handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Keep waiting for more events.
+    Continue,
+    /// Terminate immediately, dropping all queued events.
+    Stop,
+    /// Handle the events that are already queued and then terminate.
+    Drain,
+    /// Terminate immediately, dropping all queued events, and report the given exit code.
+    Exit(i32),
+}
+
+impl From<bool> for Flow {
+    /// Maps `true` to `Flow::Continue` and `false` to `Flow::Stop`.
+    fn from(running: bool) -> Flow {
+        if running {
+            Flow::Continue
+        } else {
+            Flow::Stop
+        }
+    }
+}
+
+/// Reason for which the event loop terminated. Returned together with the handler output.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// All senders were dropped and no events or timers were pending.
+    Disconnected,
+    /// The handler returned `Flow::Stop` or `Flow::Drain`.
+    Stopped,
+    /// The handler returned `Flow::Exit` with the given code.
+    Code(i32),
+    /// Termination was requested through a `Shutdown` handle.
+    Shutdown,
+    /// The handler panicked and the `PanicPolicy` was `PanicPolicy::Stop`.
+    Panicked(Panic),
+}
+
+/// Decides what the event loop does when one of the `Handler` methods panics.
+///
+/// Panics are caught in `start`, `handle`, `handle_batch`, `idle` and in jobs run by an `Invoker`. A
+/// panic in `end` is not caught.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Terminate the event loop, call `Handler::end` and report `Exit::Panicked`.
+    #[default]
+    Stop,
+    /// Discard the event that caused the panic and keep running.
+    Skip,
+}
+
+/// Panic caught by the event loop.
+pub struct Panic {
+    payload: Box<dyn Any + Send>,
+}
+
+impl Panic {
+    fn new(payload: Box<dyn Any + Send>) -> Panic {
+        Panic { payload }
+    }
+
+    /// Message passed to `panic!`, if the payload is a string.
+    pub fn message(&self) -> Option<&str> {
+        if let Some(message) = self.payload.downcast_ref::<&'static str>() {
+            Some(message)
+        } else {
+            self.payload.downcast_ref::<String>().map(String::as_str)
+        }
+    }
+
+    /// Returns the payload of the panic, as produced by `std::panic::catch_unwind`.
+    ///
+    /// Can be passed to `std::panic::resume_unwind` to continue unwinding.
+    pub fn into_payload(self) -> Box<dyn Any + Send> {
+        self.payload
+    }
+}
+
+impl fmt::Debug for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Panic").field(&self.message()).finish()
+    }
+}
+
+impl PartialEq for Panic {
+    /// Panics are compared by their messages.
+    fn eq(&self, other: &Panic) -> bool {
+        self.message() == other.message()
+    }
+}
+
+impl Eq for Panic {}
+
+/// Tells the event loop what to do after `Handler::idle` returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Idle {
+    /// Block until the next event arrives.
+    Wait,
+    /// Call `Handler::idle` again, unless an event arrives in the meantime.
+    Again,
+}
 
 /// Handles events sent to the event loop.
 pub trait Handler<EVENT: Send>: Sized {
+    /// Value produced by `end` and returned from `run`.
+    type Output;
+
     /// Called immediately after starting the event loop.
     ///
     /// The `Sender` argument can be used to send new events. It can be cloned and passed to other threads in this method.
     ///
-    /// When the last sender is dropped and there are no events pending, the event loop terminates.
+    /// When the last sender is dropped and there are no events or active timers pending, the event loop terminates.
     fn start(&mut self, sender: Sender<EVENT>);
 
     /// Called for every event sent to the event loop.
-    fn handle(&mut self, event: EVENT) -> bool;
+    ///
+    /// The returned `Flow` decides whether the event loop keeps running. The `Context` gives access
+    /// to the event loop itself.
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow;
+
+    /// Called with all events that are pending when the event loop wakes up.
+    ///
+    /// Allows amortizing work across many events. The number of events in a batch is limited by
+    /// `Builder::batch`, which defaults to one. The default implementation passes the events to
+    /// `handle` one by one, until it returns something other than `Flow::Continue` or requests
+    /// termination through the `Context`.
+    fn handle_batch(&mut self, batch: &mut Batch<EVENT>, context: &mut Context<EVENT>) -> Flow {
+        for event in batch {
+            let flow = self.handle(event, context);
+            match context.settle(flow) {
+                Flow::Continue => context.seq += 1,
+                flow => return flow,
+            }
+        }
+        Flow::Continue
+    }
+
+    /// Called when the mailbox becomes empty after handling one or more events.
+    ///
+    /// Useful for housekeeping that should only happen when the event loop is otherwise quiet. The
+    /// default implementation does nothing and returns `Idle::Wait`.
+    fn idle(&mut self) -> Idle {
+        Idle::Wait
+    }
 
     /// Called after event loop terminates.
     ///
-    /// The default implementation does nothing and can be overriden.
-    fn end(self) {}
+    /// The returned value is handed back to the caller of `run`.
+    fn end(self) -> Self::Output;
 }
 
 /// Runs the event loop on the current thread.
-pub fn run<EVENT: Send, HANDLER: Handler<EVENT>>(mut handler: HANDLER) {
-    let (tx, rx) = std::sync::mpsc::channel();
-    handler.start(tx);
-    let mut running = true;
-    while running {
-        running = match rx.recv() {
-            Ok(event) => handler.handle(event),
-            _ => false,
-        }
-    }
-    handler.end();
+///
+/// Returns the reason for which the event loop terminated and the value produced by `Handler::end`.
+pub fn run<EVENT: Send, HANDLER: Handler<EVENT>>(handler: HANDLER) -> (Exit, HANDLER::Output) {
+    Builder::new().run(handler)
+}
+
+/// Runs the event loop on a new thread.
+///
+/// Events can be sent through the returned handle. Joining the handle yields the result of the
+/// event loop.
+///
+/// # Panics
+///
+/// Panics if the thread could not be created. Use `Builder::spawn` to handle this error.
+pub fn spawn<EVENT, HANDLER>(handler: HANDLER) -> JoinHandle<EVENT, HANDLER>
+where
+    EVENT: Send + 'static,
+    HANDLER: Handler<EVENT> + Send + 'static,
+    HANDLER::Output: Send + 'static,
+{
+    Builder::new()
+        .spawn(handler)
+        .expect("failed to spawn event loop thread")
 }
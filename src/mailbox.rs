@@ -0,0 +1,747 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use super::call::{self, CallError, Pending, Reply};
+use super::mapped::{Post, WeakPost};
+use super::timer::{Scheduled, Timer};
+
+/// Called once the event loop is done with an event sent through a `DurableSender`, or once the
+/// event is discarded by `Overflow::DropNewest` or `Overflow::DropOldest`.
+pub(crate) type Ack = Box<dyn FnOnce() + Send>;
+
+pub(crate) enum Message<EVENT> {
+    Event(EVENT, Option<Ack>),
+    Schedule(Scheduled<EVENT>),
+    // Tells the event loop to run the next job queued by an `Invoker`.
+    Job,
+    Shutdown(Request),
+}
+
+/// Termination requested through a `Shutdown` handle.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Request {
+    // Whether the queued events should be handled first.
+    pub(crate) drain: bool,
+    // Time after which the queued events are discarded.
+    pub(crate) deadline: Option<Instant>,
+}
+
+/// Decides what `Sender::send` does when a bounded mailbox is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Block the sending thread until the event loop makes room for the event.
+    #[default]
+    Block,
+    /// Fail the send, returning the event in `SendError::Full`.
+    Fail,
+    /// Discard the event being sent.
+    DropNewest,
+    /// Discard the oldest queued event to make room for the event being sent.
+    DropOldest,
+}
+
+/// Priority lane of a `Sender`.
+///
+/// Events sent with a higher priority are always handled before events with a lower priority. Events
+/// with the same priority are handled in the order in which they were sent. Scheduled events are
+/// sent with the priority of the sender which scheduled them, at the time they fire.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// For bulk events which can wait until nothing else is pending.
+    Low,
+    /// Priority of the `Sender` passed to `Handler::start`.
+    #[default]
+    Normal,
+    /// For control events which should skip ahead of all other events.
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// Error returned by `Sender` methods when an event could not be queued.
+///
+/// The rejected event is carried inside and can be recovered with `into_inner`.
+#[derive(PartialEq, Eq)]
+pub enum SendError<EVENT> {
+    /// The event loop has already terminated.
+    Disconnected(EVENT),
+    /// The mailbox is full and its `Overflow` policy is `Overflow::Fail`.
+    Full(EVENT),
+    /// The delay of a scheduled event reaches past the latest `Instant` the platform can represent.
+    DelayTooLong(EVENT),
+}
+
+impl<EVENT> SendError<EVENT> {
+    /// Returns the event that could not be sent.
+    pub fn into_inner(self) -> EVENT {
+        match self {
+            SendError::Disconnected(event)
+            | SendError::Full(event)
+            | SendError::DelayTooLong(event) => event,
+        }
+    }
+
+    pub(crate) fn map<F: FnOnce(EVENT) -> T, T>(self, f: F) -> SendError<T> {
+        match self {
+            SendError::Disconnected(event) => SendError::Disconnected(f(event)),
+            SendError::Full(event) => SendError::Full(f(event)),
+            SendError::DelayTooLong(event) => SendError::DelayTooLong(f(event)),
+        }
+    }
+}
+
+impl<EVENT> fmt::Debug for SendError<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+            SendError::Full(_) => f.write_str("Full(..)"),
+            SendError::DelayTooLong(_) => f.write_str("DelayTooLong(..)"),
+        }
+    }
+}
+
+impl<EVENT> fmt::Display for SendError<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Disconnected(_) => f.write_str("sending on a terminated event loop"),
+            SendError::Full(_) => f.write_str("sending on a full mailbox"),
+            SendError::DelayTooLong(_) => f.write_str("scheduling an event too far in the future"),
+        }
+    }
+}
+
+impl<EVENT> Error for SendError<EVENT> {}
+
+/// Sending half of the event loop mailbox.
+///
+/// Works like `std::sync::mpsc::Sender` but can also schedule events for later delivery.
+pub struct Sender<EVENT> {
+    link: Link<EVENT>,
+    priority: Priority,
+}
+
+/// Where a `Sender` puts its messages.
+enum Link<EVENT> {
+    Mailbox(Arc<Mailbox<EVENT>>),
+    // Converts the messages for a sender of another event type. Created by `Sender::map`.
+    Mapped(Arc<dyn Post<EVENT>>),
+}
+
+impl<EVENT> Sender<EVENT> {
+    /// Sends an event to the event loop.
+    ///
+    /// Fails, returning the event, if the event loop has already terminated. When the mailbox is
+    /// bounded and full, the outcome depends on its `Overflow` policy.
+    pub fn send(&self, event: EVENT) -> Result<(), SendError<EVENT>> {
+        self.push(self.priority, Message::Event(event, None))
+            .map_err(|error| error.map(Message::into_event))
+    }
+
+    /// Sends an event which is acknowledged by calling `ack` once it has been handled.
+    ///
+    /// `ack` is dropped without being called if the event never gets handled.
+    pub(crate) fn send_acked(&self, event: EVENT, ack: Ack) -> Result<(), SendError<EVENT>> {
+        self.push(self.priority, Message::Event(event, Some(ack)))
+            .map_err(|error| error.map(Message::into_event))
+    }
+
+    /// Delivers an event to the event loop once `delay` elapses.
+    ///
+    /// The event is queued with the priority of this sender when it fires. Scheduled events don't
+    /// count towards the capacity of the mailbox until they fire. Fails with
+    /// `SendError::DelayTooLong` if the deadline can't be represented by `Instant`.
+    pub fn send_after(&self, delay: Duration, event: EVENT) -> Result<Timer, SendError<EVENT>> {
+        let (scheduled, timer) =
+            Scheduled::once(delay, self.priority, event).map_err(SendError::DelayTooLong)?;
+        self.schedule(scheduled, timer)
+    }
+
+    /// Delivers a clone of an event to the event loop every `period`, starting one `period` from now.
+    ///
+    /// When the handler falls behind, deliveries are skipped rather than piling up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn send_every(&self, period: Duration, event: EVENT) -> Result<Timer, SendError<EVENT>>
+    where
+        EVENT: Clone,
+    {
+        assert!(
+            period > Duration::from_secs(0),
+            "timer period must be greater than zero"
+        );
+        let (scheduled, timer) =
+            Scheduled::every(period, self.priority, event).map_err(SendError::DelayTooLong)?;
+        self.schedule(scheduled, timer)
+    }
+
+    fn schedule(
+        &self,
+        scheduled: Scheduled<EVENT>,
+        timer: Timer,
+    ) -> Result<Timer, SendError<EVENT>> {
+        self.push(self.priority, Message::Schedule(scheduled))
+            .map(|()| timer)
+            .map_err(|error| error.map(Message::into_event))
+    }
+
+    /// Sends a request built by `request` and blocks until the handler responds through its `Reply`.
+    ///
+    /// Must not be called from the thread of the event loop, which would wait for itself.
+    pub fn call<T, F>(&self, request: F) -> Result<T, CallError>
+    where
+        F: FnOnce(Reply<T>) -> EVENT,
+    {
+        self.ask(request)?.wait()
+    }
+
+    /// Like `call` but gives up with `CallError::Timeout` after `timeout`.
+    pub fn call_timeout<T, F>(&self, timeout: Duration, request: F) -> Result<T, CallError>
+    where
+        F: FnOnce(Reply<T>) -> EVENT,
+    {
+        self.ask(request)?.wait_timeout(timeout)
+    }
+
+    /// Sends a request built by `request` without waiting for the response.
+    ///
+    /// The returned `Pending` can be waited for later or awaited as a `Future`.
+    pub fn ask<T, F>(&self, request: F) -> Result<Pending<T>, SendError<EVENT>>
+    where
+        F: FnOnce(Reply<T>) -> EVENT,
+    {
+        let (reply, pending) = call::reply();
+        self.send(request(reply)).map(|()| pending)
+    }
+
+    /// Wakes up the event loop to run a job queued by an `Invoker`.
+    ///
+    /// Jobs don't count towards the capacity of the mailbox.
+    pub(crate) fn send_job(&self) -> Result<(), SendError<()>> {
+        self.push(self.priority, Message::Job)
+            .map_err(|error| error.map(|_| ()))
+    }
+
+    /// Queues `message` with the given priority, returning it if it was rejected.
+    fn push(
+        &self,
+        priority: Priority,
+        message: Message<EVENT>,
+    ) -> Result<(), SendError<Message<EVENT>>> {
+        let is_event = message.is_event();
+        let mut message = Some(message);
+        let result = self.push_with(priority, is_event, &mut || {
+            message.take().expect("message queued twice")
+        });
+        match result {
+            // The message was discarded by `Overflow::DropNewest`.
+            Ok(()) => {
+                if let Some(Message::Event(_, Some(ack))) = message {
+                    ack();
+                }
+                Ok(())
+            }
+            Err(error) => Err(error.map(|()| message.take().expect("rejected message queued"))),
+        }
+    }
+
+    /// Queues the message returned by `make`, which is called only if the message is accepted.
+    pub(crate) fn push_with(
+        &self,
+        priority: Priority,
+        is_event: bool,
+        make: &mut dyn FnMut() -> Message<EVENT>,
+    ) -> Result<(), SendError<()>> {
+        match self.link {
+            Link::Mailbox(ref mailbox) => mailbox.push(priority, is_event, make),
+            Link::Mapped(ref post) => post.push(priority, is_event, make),
+        }
+    }
+
+    /// Creates a sender which passes its messages to `post`.
+    pub(crate) fn mapped(post: Arc<dyn Post<EVENT>>, priority: Priority) -> Sender<EVENT> {
+        Sender {
+            link: Link::Mapped(post),
+            priority,
+        }
+    }
+
+    /// Returns a sender connected to the same event loop which sends events with the given priority.
+    ///
+    /// All events share the capacity of the mailbox. With `Overflow::DropOldest`, events are
+    /// discarded from the lowest priority lane first.
+    pub fn with_priority(&self, priority: Priority) -> Sender<EVENT> {
+        let mut sender = self.clone();
+        sender.priority = priority;
+        sender
+    }
+
+    /// Priority of the events sent by this sender.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Creates a handle which can terminate the event loop regardless of the remaining senders.
+    pub fn shutdown_handle(&self) -> Shutdown<EVENT> {
+        let link = match self.link {
+            Link::Mailbox(ref mailbox) => ShutdownLink::Mailbox(Arc::downgrade(mailbox)),
+            Link::Mapped(ref post) => ShutdownLink::Mapped(post.shutdown()),
+        };
+        Shutdown { link }
+    }
+
+    /// Creates a `WeakSender` with the same priority, which doesn't keep the event loop alive.
+    pub fn downgrade(&self) -> WeakSender<EVENT> {
+        let link = match self.link {
+            Link::Mailbox(ref mailbox) => WeakLink::Mailbox(Arc::downgrade(mailbox)),
+            Link::Mapped(ref post) => WeakLink::Mapped(post.downgrade()),
+        };
+        WeakSender {
+            link,
+            priority: self.priority,
+        }
+    }
+}
+
+impl<EVENT> Clone for Sender<EVENT> {
+    fn clone(&self) -> Sender<EVENT> {
+        let link = match self.link {
+            Link::Mailbox(ref mailbox) => {
+                mailbox.lock().senders += 1;
+                Link::Mailbox(mailbox.clone())
+            }
+            // The post holds a single sender of the event loop for all its clones.
+            Link::Mapped(ref post) => Link::Mapped(post.clone()),
+        };
+        Sender {
+            link,
+            priority: self.priority,
+        }
+    }
+}
+
+impl<EVENT> Drop for Sender<EVENT> {
+    fn drop(&mut self) {
+        if let Link::Mailbox(ref mailbox) = self.link {
+            let mut state = mailbox.lock();
+            state.senders -= 1;
+            if state.senders == 0 {
+                mailbox.readable.notify_all();
+            }
+        }
+    }
+}
+
+impl<EVENT> fmt::Debug for Sender<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+/// Reference to an event loop which doesn't keep it alive. Created by `Sender::downgrade`.
+///
+/// Can be handed to long-lived observers which should stop sending events once the event loop is no
+/// longer needed.
+pub struct WeakSender<EVENT> {
+    link: WeakLink<EVENT>,
+    priority: Priority,
+}
+
+enum WeakLink<EVENT> {
+    Mailbox(Weak<Mailbox<EVENT>>),
+    Mapped(Arc<dyn WeakPost<EVENT>>),
+}
+
+impl<EVENT> WeakSender<EVENT> {
+    /// Returns a new `Sender`, unless all senders have been dropped or the event loop has terminated.
+    pub fn upgrade(&self) -> Option<Sender<EVENT>> {
+        self.connect(false)
+    }
+
+    /// Returns a new `Sender`, unless the event loop has terminated.
+    ///
+    /// Unlike `upgrade`, succeeds after all senders have been dropped. Meant for the event loop
+    /// itself, which keeps running until the new sender is dropped.
+    pub(crate) fn reconnect(&self) -> Option<Sender<EVENT>> {
+        self.connect(true)
+    }
+
+    /// Priority of the events sent by the senders returned from `upgrade`.
+    pub(crate) fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Creates a weak sender which passes its messages to `post` once connected.
+    pub(crate) fn mapped(post: Arc<dyn WeakPost<EVENT>>, priority: Priority) -> WeakSender<EVENT> {
+        WeakSender {
+            link: WeakLink::Mapped(post),
+            priority,
+        }
+    }
+
+    /// Returns a new `Sender` like `upgrade`, or like `reconnect` if `disconnected` is `true`.
+    pub(crate) fn connect(&self, disconnected: bool) -> Option<Sender<EVENT>> {
+        let link = match self.link {
+            WeakLink::Mailbox(ref mailbox) => {
+                let mailbox = mailbox.upgrade()?;
+                {
+                    let mut state = mailbox.lock();
+                    if state.closed || (state.senders == 0 && !disconnected) {
+                        return None;
+                    }
+                    state.senders += 1;
+                }
+                Link::Mailbox(mailbox)
+            }
+            WeakLink::Mapped(ref post) => Link::Mapped(post.connect(disconnected)?),
+        };
+        Some(Sender {
+            link,
+            priority: self.priority,
+        })
+    }
+}
+
+impl<EVENT> Clone for WeakSender<EVENT> {
+    fn clone(&self) -> WeakSender<EVENT> {
+        let link = match self.link {
+            WeakLink::Mailbox(ref mailbox) => WeakLink::Mailbox(mailbox.clone()),
+            WeakLink::Mapped(ref post) => WeakLink::Mapped(post.clone()),
+        };
+        WeakSender {
+            link,
+            priority: self.priority,
+        }
+    }
+}
+
+impl<EVENT> fmt::Debug for WeakSender<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WeakSender")
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+/// Handle which terminates the event loop when asked to. Created by `Sender::shutdown_handle`.
+///
+/// Doesn't keep the event loop alive. Requests made after the event loop has terminated have no
+/// effect. A request overrides the previous one if the event loop hasn't received it yet.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Exit, Flow, Handler, Sender, spawn};
+///
+/// struct Service;
+///
+/// impl Handler<()> for Service {
+///     type Output = ();
+///     fn start(&mut self, _: Sender<()>) {}
+///     fn handle(&mut self, _: (), _: &mut Context<()>) -> Flow {
+///         Flow::Continue
+///     }
+///     fn end(self) {}
+/// }
+///
+/// let looper = spawn(Service);
+/// // Senders spread across threads would normally keep the event loop alive.
+/// let sender = looper.sender().clone();
+/// looper.sender().shutdown_handle().drain();
+/// assert_eq!(looper.join().unwrap().0, Exit::Shutdown);
+/// # drop(sender);
+/// ```
+pub struct Shutdown<EVENT> {
+    link: ShutdownLink<EVENT>,
+}
+
+enum ShutdownLink<EVENT> {
+    Mailbox(Weak<Mailbox<EVENT>>),
+    Mapped(Arc<dyn Fn(Request) + Send + Sync>),
+}
+
+impl<EVENT> Shutdown<EVENT> {
+    /// Terminates the event loop as soon as the current call to the handler returns, dropping all
+    /// queued events.
+    pub fn stop(&self) {
+        self.request(Request {
+            drain: false,
+            deadline: None,
+        });
+    }
+
+    /// Handles the events that are already queued and then terminates the event loop.
+    pub fn drain(&self) {
+        self.request(Request {
+            drain: true,
+            deadline: None,
+        });
+    }
+
+    /// Like `drain` but discards the events which are still queued once `timeout` elapses.
+    ///
+    /// The deadline is checked between calls to the handler, which are not interrupted.
+    pub fn drain_timeout(&self, timeout: Duration) {
+        self.request(Request {
+            drain: true,
+            deadline: Some(Instant::now() + timeout),
+        });
+    }
+
+    pub(crate) fn request(&self, request: Request) {
+        let mailbox = match self.link {
+            ShutdownLink::Mailbox(ref mailbox) => mailbox,
+            ShutdownLink::Mapped(ref request_shutdown) => return request_shutdown(request),
+        };
+        if let Some(mailbox) = mailbox.upgrade() {
+            let mut state = mailbox.lock();
+            if !state.closed {
+                state.shutdown = Some(request);
+                mailbox.readable.notify_one();
+            }
+        }
+    }
+}
+
+impl<EVENT> Clone for Shutdown<EVENT> {
+    fn clone(&self) -> Shutdown<EVENT> {
+        let link = match self.link {
+            ShutdownLink::Mailbox(ref mailbox) => ShutdownLink::Mailbox(mailbox.clone()),
+            ShutdownLink::Mapped(ref request) => ShutdownLink::Mapped(request.clone()),
+        };
+        Shutdown { link }
+    }
+}
+
+impl<EVENT> fmt::Debug for Shutdown<EVENT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Shutdown { .. }")
+    }
+}
+
+/// Receiving half of the event loop mailbox. Owned by the event loop.
+pub(crate) struct Receiver<EVENT> {
+    mailbox: Arc<Mailbox<EVENT>>,
+}
+
+impl<EVENT> Receiver<EVENT> {
+    /// Blocks until a message is queued, all senders are dropped or the `deadline` passes.
+    ///
+    /// When a `deadline` is given, keeps waiting for it after all senders are dropped, so that a
+    /// shutdown request can still be received.
+    pub(crate) fn recv(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<Message<EVENT>, RecvTimeoutError> {
+        let mut state = self.mailbox.lock();
+        loop {
+            if let Some(message) = self.mailbox.pop(&mut state) {
+                return Ok(message);
+            }
+            if state.senders == 0 && deadline.is_none() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match deadline {
+                None => self.mailbox.readable.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    let timeout = deadline - now;
+                    self.mailbox
+                        .readable
+                        .wait_timeout(state, timeout)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+
+    /// Returns the next queued message without blocking.
+    pub(crate) fn try_recv(&self) -> Option<Message<EVENT>> {
+        let mut state = self.mailbox.lock();
+        self.mailbox.pop(&mut state)
+    }
+
+    /// Queues the event of a timer which fired behind the messages that are already queued in its
+    /// priority lane.
+    ///
+    /// Ignores the capacity of the mailbox, since the event loop must not wait for itself.
+    pub(crate) fn deliver(&self, priority: Priority, event: EVENT) {
+        let mut state = self.mailbox.lock();
+        state.events += 1;
+        state.lanes[priority.lane()].push_back(Message::Event(event, None));
+    }
+}
+
+impl<EVENT> Drop for Receiver<EVENT> {
+    fn drop(&mut self) {
+        let queue = {
+            let mut state = self.mailbox.lock();
+            state.closed = true;
+            state.events = 0;
+            self.mailbox.writable.notify_all();
+            mem::take(&mut state.lanes)
+        };
+        // Events may own senders of this mailbox so they are dropped without holding the lock.
+        drop(queue);
+    }
+}
+
+/// Creates a connected `Sender` and `Receiver` pair.
+///
+/// When `capacity` is set, at most that many events are queued at once and `overflow` decides what
+/// happens to further events.
+pub(crate) fn channel<EVENT>(
+    capacity: Option<usize>,
+    overflow: Overflow,
+) -> (Sender<EVENT>, Receiver<EVENT>) {
+    let mailbox = Arc::new(Mailbox {
+        state: Mutex::new(State {
+            lanes: (0..Priority::COUNT).map(|_| VecDeque::new()).collect(),
+            events: 0,
+            senders: 1,
+            closed: false,
+            shutdown: None,
+        }),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+        capacity,
+        overflow,
+    });
+    let sender = Sender {
+        link: Link::Mailbox(mailbox.clone()),
+        priority: Priority::Normal,
+    };
+    (sender, Receiver { mailbox })
+}
+
+struct State<EVENT> {
+    // One queue per `Priority`, indexed by `Priority::lane`.
+    lanes: Vec<VecDeque<Message<EVENT>>>,
+    // Number of `Message::Event` entries in all `lanes`.
+    events: usize,
+    senders: usize,
+    closed: bool,
+    // Delivered before any other message.
+    shutdown: Option<Request>,
+}
+
+struct Mailbox<EVENT> {
+    state: Mutex<State<EVENT>>,
+    readable: Condvar,
+    writable: Condvar,
+    capacity: Option<usize>,
+    overflow: Overflow,
+}
+
+impl<EVENT> Mailbox<EVENT> {
+    fn lock(&self) -> MutexGuard<'_, State<EVENT>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Queues the message returned by `make`, which is called only if the message is accepted.
+    fn push(
+        &self,
+        priority: Priority,
+        is_event: bool,
+        make: &mut dyn FnMut() -> Message<EVENT>,
+    ) -> Result<(), SendError<()>> {
+        let mut dropped = None;
+        let result = {
+            let mut state = self.lock();
+            loop {
+                if state.closed {
+                    break Err(SendError::Disconnected(()));
+                }
+                let full = self
+                    .capacity
+                    .is_some_and(|capacity| state.events >= capacity);
+                if !is_event || !full {
+                    if is_event {
+                        state.events += 1;
+                    }
+                    state.lanes[priority.lane()].push_back(make());
+                    self.readable.notify_one();
+                    break Ok(());
+                }
+                match self.overflow {
+                    Overflow::Block => state = self.writable.wait(state).unwrap(),
+                    Overflow::Fail => break Err(SendError::Full(())),
+                    Overflow::DropNewest => break Ok(()),
+                    Overflow::DropOldest => {
+                        dropped = state.lanes.iter_mut().find_map(|lane| {
+                            let oldest = lane.iter().position(Message::is_event)?;
+                            lane.remove(oldest)
+                        });
+                        state.events -= 1;
+                    }
+                }
+            }
+        };
+        // Dropped outside of the lock, for the same reason as in `Receiver::drop`.
+        if let Some(Message::Event(_, Some(ack))) = dropped {
+            ack();
+        }
+        result
+    }
+
+    fn pop(&self, state: &mut State<EVENT>) -> Option<Message<EVENT>> {
+        if let Some(request) = state.shutdown.take() {
+            return Some(Message::Shutdown(request));
+        }
+        let message = state.lanes.iter_mut().rev().find_map(VecDeque::pop_front)?;
+        if message.is_event() {
+            state.events -= 1;
+            self.writable.notify_one();
+        }
+        Some(message)
+    }
+}
+
+impl<EVENT> Message<EVENT> {
+    pub(crate) fn is_event(&self) -> bool {
+        match *self {
+            Message::Event(..) => true,
+            Message::Schedule(_) | Message::Job | Message::Shutdown(_) => false,
+        }
+    }
+
+    /// Converts the event carried by this message with `map`.
+    pub(crate) fn map<T, F>(self, map: &Arc<F>) -> Message<T>
+    where
+        EVENT: Send + 'static,
+        F: Fn(EVENT) -> T + Send + Sync + 'static,
+    {
+        match self {
+            Message::Event(event, ack) => Message::Event(map(event), ack),
+            Message::Schedule(scheduled) => Message::Schedule(scheduled.map(map)),
+            Message::Job => Message::Job,
+            Message::Shutdown(request) => Message::Shutdown(request),
+        }
+    }
+
+    fn into_event(self) -> EVENT {
+        match self {
+            Message::Event(event, _) => event,
+            Message::Schedule(scheduled) => scheduled.into_event(),
+            Message::Job | Message::Shutdown(_) => {
+                unreachable!("only events and timers are returned to senders")
+            }
+        }
+    }
+}
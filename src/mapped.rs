@@ -0,0 +1,246 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::mailbox::{Message, Priority, Request, SendError, Sender, Shutdown, WeakSender};
+use super::timer::Timer;
+
+impl<EVENT: Send + 'static> Sender<EVENT> {
+    /// Returns a sender of events of type `T`, which are converted to `EVENT` by `map`.
+    ///
+    /// Lets components post their own events without knowing the event type of the loop. Use
+    /// `sender.map(EVENT::from)` for types which can be converted with `From`.
+    ///
+    /// ```rust
+    /// use mrogalski_looper::{Context, Flow, Handler, MappedSender, Sender, run};
+    ///
+    /// enum Event {
+    ///     Progress(u8),
+    ///     Done,
+    /// }
+    ///
+    /// // Library code which only knows about its own events.
+    /// fn download(progress: MappedSender<u8>) {
+    ///     for percent in [50, 100] {
+    ///         progress.send(percent).unwrap();
+    ///     }
+    /// }
+    ///
+    /// struct App(Vec<u8>);
+    ///
+    /// impl Handler<Event> for App {
+    ///     type Output = Vec<u8>;
+    ///     fn start(&mut self, sender: Sender<Event>) {
+    ///         download(sender.map(Event::Progress));
+    ///         sender.send(Event::Done).unwrap();
+    ///     }
+    ///     fn handle(&mut self, event: Event, _: &mut Context<Event>) -> Flow {
+    ///         match event {
+    ///             Event::Progress(percent) => self.0.push(percent),
+    ///             Event::Done => return Flow::Stop,
+    ///         }
+    ///         Flow::Continue
+    ///     }
+    ///     fn end(self) -> Vec<u8> {
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(run(App(vec![])).1, vec![50, 100]);
+    /// ```
+    pub fn map<T, F>(&self, map: F) -> MappedSender<T>
+    where
+        T: Send + 'static,
+        F: Fn(T) -> EVENT + Send + Sync + 'static,
+    {
+        let mapped = Mapped {
+            sender: self.clone(),
+            map: Arc::new(map),
+        };
+        MappedSender {
+            sender: Sender::mapped(Arc::new(mapped), self.priority()),
+        }
+    }
+}
+
+impl<EVENT: Send + 'static> WeakSender<EVENT> {
+    /// Returns a weak sender of events of type `T`, which are converted to `EVENT` by `map`.
+    pub(crate) fn map<T, F>(&self, map: F) -> WeakSender<T>
+    where
+        T: Send + 'static,
+        F: Fn(T) -> EVENT + Send + Sync + 'static,
+    {
+        let mapped = WeakMapped {
+            sender: self.clone(),
+            map: Arc::new(map),
+        };
+        WeakSender::mapped(Arc::new(mapped), self.priority())
+    }
+}
+
+/// Sender of events of type `T` into an event loop with a different event type.
+///
+/// Created by `Sender::map`. Counts as a `Sender` of the event loop. Events are converted only once
+/// they are accepted, so events which could not be sent are returned in the `SendError`.
+pub struct MappedSender<T> {
+    sender: Sender<T>,
+}
+
+impl<T> MappedSender<T> {
+    /// Sends an event to the event loop. Works like `Sender::send`.
+    pub fn send(&self, event: T) -> Result<(), SendError<T>> {
+        self.sender.send(event)
+    }
+
+    /// Delivers an event to the event loop once `delay` elapses. Works like `Sender::send_after`.
+    pub fn send_after(&self, delay: Duration, event: T) -> Result<Timer, SendError<T>> {
+        self.sender.send_after(delay, event)
+    }
+
+    /// Delivers a clone of an event to the event loop every `period`. Works like
+    /// `Sender::send_every`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn send_every(&self, period: Duration, event: T) -> Result<Timer, SendError<T>>
+    where
+        T: Clone,
+    {
+        self.sender.send_every(period, event)
+    }
+
+    /// Creates a `WeakMappedSender`, which doesn't keep the event loop alive.
+    pub fn downgrade(&self) -> WeakMappedSender<T> {
+        WeakMappedSender {
+            sender: self.sender.downgrade(),
+        }
+    }
+
+    /// Creates a handle which can terminate the event loop regardless of the remaining senders.
+    pub fn shutdown_handle(&self) -> Shutdown<T> {
+        self.sender.shutdown_handle()
+    }
+
+    /// Returns a `Sender` of `T` for handlers which handle `T` inside of the event loop.
+    pub(crate) fn into_sender(self) -> Sender<T> {
+        self.sender
+    }
+}
+
+impl<T> Clone for MappedSender<T> {
+    fn clone(&self) -> MappedSender<T> {
+        MappedSender {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for MappedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MappedSender { .. }")
+    }
+}
+
+/// Reference to an event loop which doesn't keep it alive. Created by `MappedSender::downgrade`.
+pub struct WeakMappedSender<T> {
+    sender: WeakSender<T>,
+}
+
+impl<T> WeakMappedSender<T> {
+    /// Returns a new `MappedSender`, unless all senders have been dropped or the event loop has
+    /// terminated.
+    pub fn upgrade(&self) -> Option<MappedSender<T>> {
+        let sender = self.sender.upgrade()?;
+        Some(MappedSender { sender })
+    }
+}
+
+impl<T> Clone for WeakMappedSender<T> {
+    fn clone(&self) -> WeakMappedSender<T> {
+        WeakMappedSender {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for WeakMappedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("WeakMappedSender { .. }")
+    }
+}
+
+/// Passes the messages of a mapped `Sender` to a sender of another event type.
+pub(crate) trait Post<T>: Send + Sync {
+    /// Queues the message returned by `make`, which is called only if the message is accepted.
+    fn push(
+        &self,
+        priority: Priority,
+        is_event: bool,
+        make: &mut dyn FnMut() -> Message<T>,
+    ) -> Result<(), SendError<()>>;
+
+    fn downgrade(&self) -> Arc<dyn WeakPost<T>>;
+
+    /// Returns a function which requests termination of the event loop.
+    fn shutdown(&self) -> Arc<dyn Fn(Request) + Send + Sync>;
+}
+
+/// Post which doesn't keep the event loop alive. Held by a mapped `WeakSender`.
+pub(crate) trait WeakPost<T>: Send + Sync {
+    fn connect(&self, disconnected: bool) -> Option<Arc<dyn Post<T>>>;
+}
+
+struct Mapped<EVENT, F> {
+    sender: Sender<EVENT>,
+    map: Arc<F>,
+}
+
+impl<T, EVENT, F> Post<T> for Mapped<EVENT, F>
+where
+    T: Send + 'static,
+    EVENT: Send + 'static,
+    F: Fn(T) -> EVENT + Send + Sync + 'static,
+{
+    fn push(
+        &self,
+        priority: Priority,
+        is_event: bool,
+        make: &mut dyn FnMut() -> Message<T>,
+    ) -> Result<(), SendError<()>> {
+        self.sender
+            .push_with(priority, is_event, &mut || make().map(&self.map))
+    }
+
+    fn downgrade(&self) -> Arc<dyn WeakPost<T>> {
+        Arc::new(WeakMapped {
+            sender: self.sender.downgrade(),
+            map: self.map.clone(),
+        })
+    }
+
+    fn shutdown(&self) -> Arc<dyn Fn(Request) + Send + Sync> {
+        let shutdown = self.sender.shutdown_handle();
+        Arc::new(move |request| shutdown.request(request))
+    }
+}
+
+struct WeakMapped<EVENT, F> {
+    sender: WeakSender<EVENT>,
+    map: Arc<F>,
+}
+
+impl<T, EVENT, F> WeakPost<T> for WeakMapped<EVENT, F>
+where
+    T: Send + 'static,
+    EVENT: Send + 'static,
+    F: Fn(T) -> EVENT + Send + Sync + 'static,
+{
+    fn connect(&self, disconnected: bool) -> Option<Arc<dyn Post<T>>> {
+        let sender = self.sender.connect(disconnected)?;
+        Some(Arc::new(Mapped {
+            sender,
+            map: self.map.clone(),
+        }))
+    }
+}
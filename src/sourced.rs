@@ -0,0 +1,232 @@
+use std::cmp;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::context::Context;
+use super::journal::{numbered, numbered_name, Codec, Journal, Replay};
+use super::mailbox::{channel, Overflow, Sender};
+use super::{Flow, Handler, Idle, PanicPolicy};
+
+const SNAPSHOT: &str = "snapshot";
+// Snapshot being written. Left behind if the process dies before it's renamed.
+const PARTIAL: &str = "tmp";
+
+/// Handler whose state survives restarts, rebuilt from a snapshot and a journal of events.
+///
+/// Every event is recorded in a `Journal` before it's handled. Every few events, and when the
+/// event loop terminates, the handler itself is saved as a snapshot through the `STATE` codec.
+/// `open` loads the latest snapshot and replays the events recorded after it, so the handler
+/// continues where it left off, even if the process died.
+///
+/// Snapshots and journal segments share the directory. Only the latest snapshot is kept, and the
+/// journal segments which hold only events covered by it are removed.
+///
+/// ```rust
+/// use mrogalski_looper::{Codec, Context, Flow, Handler, Sender, Sourced, run};
+/// use std::io;
+///
+/// struct Bytes;
+///
+/// impl Codec<u8> for Bytes {
+///     fn encode(&mut self, event: &u8, buf: &mut Vec<u8>) -> io::Result<()> {
+///         buf.push(*event);
+///         Ok(())
+///     }
+///     fn decode(&mut self, bytes: &[u8]) -> io::Result<u8> {
+///         Ok(bytes[0])
+///     }
+/// }
+///
+/// struct Sum(u8);
+///
+/// impl Codec<Sum> for Bytes {
+///     fn encode(&mut self, sum: &Sum, buf: &mut Vec<u8>) -> io::Result<()> {
+///         buf.push(sum.0);
+///         Ok(())
+///     }
+///     fn decode(&mut self, bytes: &[u8]) -> io::Result<Sum> {
+///         Ok(Sum(bytes[0]))
+///     }
+/// }
+///
+/// impl Handler<u8> for Sum {
+///     type Output = u8;
+///     fn start(&mut self, sender: Sender<u8>) {
+///         sender.send(self.0 + 1).unwrap();
+///     }
+///     fn handle(&mut self, i: u8, _: &mut Context<u8>) -> Flow {
+///         self.0 += i;
+///         Flow::Continue
+///     }
+///     fn end(self) -> u8 {
+///         self.0
+///     }
+/// }
+///
+/// let dir = std::env::temp_dir().join(format!("looper-sourced-doc-{}", std::process::id()));
+/// let sourced = Sourced::open(&dir, Bytes, Bytes, || Sum(0)).unwrap();
+/// assert_eq!(run(sourced).1.unwrap(), 1);
+/// let sourced = Sourced::open(&dir, Bytes, Bytes, || Sum(0)).unwrap();
+/// assert_eq!(run(sourced).1.unwrap(), 3);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct Sourced<HANDLER, EVENTS, STATE> {
+    journal: Journal<HANDLER, EVENTS>,
+    state: STATE,
+    dir: PathBuf,
+    // Sequence number of the next event, which is the number of events handled so far, including
+    // the ones covered by the snapshot.
+    position: u64,
+    // Position of the latest snapshot, which covers the events numbered below it.
+    snapshot: u64,
+    interval: u64,
+    error: Option<io::Error>,
+}
+
+impl<HANDLER, EVENTS, STATE> Sourced<HANDLER, EVENTS, STATE> {
+    /// Restores the handler stored in `dir`, or creates it with `init` if there is no snapshot.
+    ///
+    /// The events recorded after the snapshot are passed to `Handler::handle` before the handler
+    /// is started. Events sent through the `Context` while replaying are discarded, since the events
+    /// sent originally are already in the journal. An event whose handling panics is skipped, like
+    /// with `PanicPolicy::Skip`, since it would panic again every time the handler is restored.
+    /// Snapshots left unfinished by a previous run are removed.
+    pub fn open<EVENT, P, INIT>(
+        dir: P,
+        events: EVENTS,
+        mut state: STATE,
+        init: INIT,
+    ) -> io::Result<Sourced<HANDLER, EVENTS, STATE>>
+    where
+        EVENT: Send,
+        HANDLER: Handler<EVENT>,
+        EVENTS: Codec<EVENT>,
+        STATE: Codec<HANDLER>,
+        P: AsRef<Path>,
+        INIT: FnOnce() -> HANDLER,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        for (_, partial) in numbered(&dir, PARTIAL)? {
+            fs::remove_file(partial)?;
+        }
+        let (mut handler, snapshot) = match numbered(&dir, SNAPSHOT)?.pop() {
+            Some((position, path)) => (state.decode(&fs::read(path)?)?, position),
+            None => (init(), 0),
+        };
+        let (sender, _rx) = channel(None, Overflow::default());
+        let mut context = Context::new(&sender);
+        let mut replay = Replay::new(&dir, events)?.from(snapshot);
+        replay.feed(&mut handler, &mut context, PanicPolicy::Skip)?;
+        let journal = Journal::new(handler, replay.into_codec(), &dir)?;
+        Ok(Sourced {
+            // The journal may be empty if all its events are covered by the snapshot.
+            position: cmp::max(journal.next_seq(), snapshot),
+            journal,
+            state,
+            dir,
+            snapshot,
+            interval: 1000,
+            error: None,
+        })
+    }
+
+    /// Takes a snapshot after every `events` events. Defaults to 1000.
+    ///
+    /// Panics if `events` is zero.
+    pub fn snapshot_every(mut self, events: u64) -> Sourced<HANDLER, EVENTS, STATE> {
+        assert!(events > 0, "snapshot interval must be greater than zero");
+        self.interval = events;
+        self
+    }
+
+    /// Sets the size of the journal segments. See `Journal::segment_size`.
+    pub fn segment_size(mut self, bytes: u64) -> Sourced<HANDLER, EVENTS, STATE> {
+        self.journal = self.journal.segment_size(bytes);
+        self
+    }
+
+    /// Saves the handler and removes the older snapshots and the journal segments they cover.
+    fn snapshot(&mut self) -> io::Result<()>
+    where
+        STATE: Codec<HANDLER>,
+    {
+        let mut buf = vec![];
+        self.state.encode(self.journal.handler(), &mut buf)?;
+        // The snapshot must not cover events which could still be lost.
+        self.journal.sync()?;
+        let path = self.dir.join(numbered_name(self.position, SNAPSHOT));
+        let tmp = path.with_extension(PARTIAL);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        for (position, old) in numbered(&self.dir, SNAPSHOT)? {
+            if position < self.position {
+                fs::remove_file(old)?;
+            }
+        }
+        self.snapshot = self.position;
+        self.journal.compact(self.position)
+    }
+}
+
+impl<EVENT, HANDLER, EVENTS, STATE> Handler<EVENT> for Sourced<HANDLER, EVENTS, STATE>
+where
+    EVENT: Send,
+    HANDLER: Handler<EVENT>,
+    EVENTS: Codec<EVENT>,
+    STATE: Codec<HANDLER>,
+{
+    type Output = io::Result<HANDLER::Output>;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        self.journal.handler_mut().start(sender);
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        if let Err(error) = self.journal.record(&event, self.position) {
+            self.error = Some(error);
+            return Flow::Stop;
+        }
+        // Counted before handling, so that the next event gets the next number even if handling this
+        // one panics and the event loop skips it. `open` skips the event too if it's replayed.
+        self.position += 1;
+        let flow = self.journal.handler_mut().handle(event, context);
+        if self.position - self.snapshot >= self.interval {
+            if let Err(error) = self.snapshot() {
+                self.error = Some(error);
+                return Flow::Stop;
+            }
+        }
+        flow
+    }
+
+    fn idle(&mut self) -> Idle {
+        self.journal.handler_mut().idle()
+    }
+
+    fn end(mut self) -> io::Result<HANDLER::Output> {
+        if self.error.is_none() && self.position > self.snapshot {
+            self.error = self.snapshot().err();
+        }
+        let output = self.journal.end()?;
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(output),
+        }
+    }
+}
+
+impl<HANDLER, EVENTS, STATE> fmt::Debug for Sourced<HANDLER, EVENTS, STATE> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sourced")
+            .field("journal", &self.journal)
+            .field("position", &self.position)
+            .field("snapshot", &self.snapshot)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
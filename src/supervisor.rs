@@ -0,0 +1,281 @@
+use std::any::Any;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::mailbox::{Sender, WeakSender};
+use super::{Batch, Context, Flow, Handler, Idle, Panic};
+
+type RestartCallback = Box<dyn FnMut(&Restart) + Send>;
+
+/// Handler which replaces a panicking handler with a fresh one produced by a factory.
+///
+/// Restarts happen inside the event loop, so senders and queued events are not affected by them.
+/// The event that caused the panic is discarded. When the restart limit is exceeded, the
+/// supervisor gives up and re-raises the last panic, which is then handled according to the
+/// `PanicPolicy` of the event loop.
+///
+/// ```rust
+/// use mrogalski_looper::{Context, Flow, Handler, Sender, Supervisor, run};
+/// use std::time::Duration;
+///
+/// struct Worker(Vec<i32>);
+///
+/// impl Handler<i32> for Worker {
+///     type Output = Vec<i32>;
+///     fn start(&mut self, sender: Sender<i32>) {
+///         // Only the first worker sends the events.
+///         if self.0.is_empty() {
+///             self.0.push(0);
+///             for i in 1..4 {
+///                 sender.send(i).unwrap();
+///             }
+///         }
+///     }
+///     fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+///         assert!(i != 2, "two is not allowed");
+///         self.0.push(i);
+///         Flow::Continue
+///     }
+///     fn end(self) -> Vec<i32> {
+///         self.0
+///     }
+/// }
+///
+/// let mut generation = 0;
+/// let supervisor = Supervisor::new(move || {
+///     generation += 1;
+///     Worker(if generation == 1 { vec![] } else { vec![-generation] })
+/// })
+/// .max_restarts(3, Duration::from_secs(1))
+/// .on_restart(|restart| assert_eq!(restart.count(), 1));
+///
+/// assert_eq!(run(supervisor).1, vec![-2, 3]);
+/// ```
+pub struct Supervisor<EVENT, HANDLER, FACTORY> {
+    factory: FACTORY,
+    handler: Option<HANDLER>,
+    sender: Option<WeakSender<EVENT>>,
+    max_restarts: Option<(usize, Duration)>,
+    backoff: Option<(Duration, Duration)>,
+    on_restart: Option<RestartCallback>,
+    // Times of the restarts within the `max_restarts` window.
+    recent: VecDeque<Instant>,
+    // Restarts since the last event handled without a panic.
+    consecutive: u32,
+    count: usize,
+    failed: bool,
+}
+
+impl<EVENT, HANDLER, FACTORY> Supervisor<EVENT, HANDLER, FACTORY>
+where
+    EVENT: Send,
+    HANDLER: Handler<EVENT>,
+    FACTORY: FnMut() -> HANDLER,
+{
+    /// Creates a supervisor which obtains handlers from `factory`.
+    ///
+    /// By default the handler is restarted immediately and without limits.
+    pub fn new(factory: FACTORY) -> Supervisor<EVENT, HANDLER, FACTORY> {
+        Supervisor {
+            factory,
+            handler: None,
+            sender: None,
+            max_restarts: None,
+            backoff: None,
+            on_restart: None,
+            recent: VecDeque::new(),
+            consecutive: 0,
+            count: 0,
+            failed: false,
+        }
+    }
+
+    /// Gives up after more than `restarts` restarts within the time window of length `within`.
+    pub fn max_restarts(mut self, restarts: usize, within: Duration) -> Self {
+        self.max_restarts = Some((restarts, within));
+        self
+    }
+
+    /// Waits before restarting the handler.
+    ///
+    /// The first restart waits for `initial`. Each consecutive restart waits twice as long as the
+    /// previous one, up to `max`. The delay is reset once the handler handles an event without
+    /// panicking. The event loop doesn't handle any events while waiting.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Some((initial, max));
+        self
+    }
+
+    /// Calls `callback` before every restart.
+    pub fn on_restart<CALLBACK>(mut self, callback: CALLBACK) -> Self
+    where
+        CALLBACK: FnMut(&Restart) + Send + 'static,
+    {
+        self.on_restart = Some(Box::new(callback));
+        self
+    }
+
+    /// Calls `f` on the current handler, restarting it if `f` panics.
+    ///
+    /// Returns `None` if the handler was restarted.
+    fn supervise<R, F: FnOnce(&mut HANDLER) -> R>(&mut self, f: F) -> Option<R> {
+        let result = {
+            let handler = self.handler.as_mut().expect("handler not started");
+            panic::catch_unwind(AssertUnwindSafe(|| f(handler)))
+        };
+        match result {
+            Ok(result) => Some(result),
+            Err(payload) => {
+                self.restart(payload);
+                None
+            }
+        }
+    }
+
+    /// Replaces the current handler with a started, fresh one.
+    fn restart(&mut self, mut payload: Box<dyn Any + Send>) {
+        loop {
+            let now = Instant::now();
+            if let Some((restarts, within)) = self.max_restarts {
+                while self
+                    .recent
+                    .front()
+                    .is_some_and(|&restart| now.duration_since(restart) >= within)
+                {
+                    self.recent.pop_front();
+                }
+                if self.recent.len() >= restarts {
+                    self.failed = true;
+                    panic::resume_unwind(payload);
+                }
+            }
+            let delay = self.delay();
+            self.consecutive += 1;
+            self.count += 1;
+            self.recent.push_back(now);
+            if let Some(ref mut on_restart) = self.on_restart {
+                on_restart(&Restart {
+                    count: self.count,
+                    delay,
+                    panic: Panic::new(payload),
+                });
+            }
+            thread::sleep(delay);
+            let sender = self
+                .sender
+                .as_ref()
+                .and_then(WeakSender::reconnect)
+                .expect("event loop terminated while restarting the handler");
+            let mut handler = (self.factory)();
+            let started = panic::catch_unwind(AssertUnwindSafe(|| handler.start(sender)));
+            self.handler = Some(handler);
+            match started {
+                Ok(()) => return,
+                Err(next) => payload = next,
+            }
+        }
+    }
+
+    /// Resets the backoff after a successful call. Restarts count as `Flow::Continue`.
+    fn settle(&mut self, flow: Option<Flow>) -> Flow {
+        match flow {
+            Some(flow) => {
+                self.consecutive = 0;
+                flow
+            }
+            None => Flow::Continue,
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        match self.backoff {
+            Some((initial, max)) => {
+                let factor = 1u32.checked_shl(self.consecutive).unwrap_or(u32::MAX);
+                cmp::min(initial.saturating_mul(factor), max)
+            }
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+impl<EVENT, HANDLER, FACTORY> Handler<EVENT> for Supervisor<EVENT, HANDLER, FACTORY>
+where
+    EVENT: Send,
+    HANDLER: Handler<EVENT>,
+    FACTORY: FnMut() -> HANDLER,
+{
+    /// Output of the last handler, including one that panicked when the supervisor gave up.
+    type Output = HANDLER::Output;
+
+    fn start(&mut self, sender: Sender<EVENT>) {
+        self.sender = Some(sender.downgrade());
+        self.handler = Some((self.factory)());
+        self.supervise(|handler| handler.start(sender));
+    }
+
+    fn handle(&mut self, event: EVENT, context: &mut Context<EVENT>) -> Flow {
+        if self.failed {
+            return Flow::Stop;
+        }
+        let flow = self.supervise(|handler| handler.handle(event, context));
+        self.settle(flow)
+    }
+
+    fn handle_batch(&mut self, batch: &mut Batch<EVENT>, context: &mut Context<EVENT>) -> Flow {
+        if self.failed {
+            return Flow::Stop;
+        }
+        let flow = self.supervise(|handler| handler.handle_batch(batch, context));
+        self.settle(flow)
+    }
+
+    fn idle(&mut self) -> Idle {
+        if self.failed {
+            return Idle::Wait;
+        }
+        self.supervise(Handler::idle).unwrap_or(Idle::Wait)
+    }
+
+    fn end(self) -> HANDLER::Output {
+        self.handler.expect("handler not started").end()
+    }
+}
+
+impl<EVENT, HANDLER, FACTORY> fmt::Debug for Supervisor<EVENT, HANDLER, FACTORY> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("max_restarts", &self.max_restarts)
+            .field("backoff", &self.backoff)
+            .field("restarts", &self.count)
+            .finish()
+    }
+}
+
+/// Information about a handler restart, passed to the `Supervisor::on_restart` callback.
+#[derive(Debug)]
+pub struct Restart {
+    count: usize,
+    delay: Duration,
+    panic: Panic,
+}
+
+impl Restart {
+    /// Number of restarts so far, including this one.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Time for which the supervisor waits before starting the new handler.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Panic that caused the restart.
+    pub fn panic(&self) -> &Panic {
+        &self.panic
+    }
+}
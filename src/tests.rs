@@ -1,4 +1,16 @@
 use super::*;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Default)]
 struct TestHandler {
@@ -7,14 +19,15 @@ struct TestHandler {
 }
 
 impl Handler<i32> for TestHandler {
+    type Output = ();
     fn start(&mut self, sender: Sender<i32>) {
         for elem in &self.expected {
-            sender.send(elem.clone()).unwrap();
+            sender.send(*elem).unwrap();
         }
     }
-    fn handle(&mut self, i: i32) -> bool {
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
         self.data.push(i);
-        true
+        Flow::Continue
     }
     fn end(self) {
         assert_eq!(self.data, self.expected);
@@ -36,3 +49,1540 @@ fn run_with_data() {
         ..Default::default()
     });
 }
+
+struct TimerHandler {
+    data: Vec<i32>,
+    expected: Vec<i32>,
+    repeating: Option<Timer>,
+}
+
+impl Handler<i32> for TimerHandler {
+    type Output = ();
+    fn start(&mut self, sender: Sender<i32>) {
+        sender.send_after(Duration::from_millis(20), 3).unwrap();
+        sender.send_after(Duration::from_millis(10), 2).unwrap();
+        sender.send(1).unwrap();
+        sender
+            .send_after(Duration::from_millis(5), 4)
+            .unwrap()
+            .cancel();
+        self.repeating = Some(sender.send_every(Duration::from_millis(30), 5).unwrap());
+    }
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.data.push(i);
+        if self.data.iter().filter(|&&x| x == 5).count() == 2 {
+            self.repeating.take().unwrap().cancel();
+        }
+        Flow::Continue
+    }
+    fn end(self) {
+        assert_eq!(self.data, self.expected);
+    }
+}
+
+#[test]
+fn run_with_timers() {
+    run(TimerHandler {
+        data: vec![],
+        expected: vec![1, 2, 3, 5, 5],
+        repeating: None,
+    });
+}
+
+#[test]
+fn late_repeating_timer_does_not_starve_mailbox() {
+    let (ticked, ticks) = mpsc::channel();
+    let handler = FnHandler::new(ticked, |ticked: &mut mpsc::Sender<i32>, i, _| {
+        // Slower than the period of the timer, so the timer is always due.
+        thread::sleep(Duration::from_millis(2));
+        let _ = ticked.send(i);
+        Flow::from(i != 1)
+    })
+    .on_start(|_, sender| {
+        sender.send_every(Duration::from_millis(1), 0).unwrap();
+    });
+    let looper = spawn(handler);
+    for _ in 0..3 {
+        assert_eq!(ticks.recv().unwrap(), 0);
+    }
+    looper.sender().send(1).unwrap();
+    assert_eq!(looper.join().unwrap().0, Exit::Stopped);
+
+    let looper = spawn(FnHandler::new((), |_, _: i32, _| {
+        thread::sleep(Duration::from_millis(2));
+        Flow::Continue
+    }));
+    let timer = looper.sender().send_every(Duration::from_millis(1), 0);
+    thread::sleep(Duration::from_millis(10));
+    looper.sender().shutdown_handle().stop();
+    assert_eq!(looper.join().unwrap().0, Exit::Shutdown);
+    drop(timer);
+}
+
+#[test]
+fn late_timer_fires_once_and_rearms_a_period_after_now() {
+    let period = Duration::from_millis(10);
+    let mut timers = timer::Timers::new();
+    let (scheduled, _) = timer::Scheduled::every(period, Priority::High, 1).unwrap();
+    timers.insert(scheduled);
+    let first = timers.next_deadline().unwrap();
+
+    // Slightly late, the timer keeps its schedule.
+    let now = first + Duration::from_millis(1);
+    assert_eq!(timers.pop_due(now), Some((1, Priority::High)));
+    assert_eq!(timers.next_deadline(), Some(first + period));
+
+    // Several periods late, it fires once and then waits for a whole period.
+    let now = first + period * 4;
+    assert_eq!(timers.pop_due(now), Some((1, Priority::High)));
+    assert_eq!(timers.pop_due(now), None);
+    assert_eq!(timers.next_deadline(), Some(now + period));
+}
+
+#[test]
+fn unrepresentable_delay_fails() {
+    let probe = Probe::new();
+    let once = probe.sender().send_after(Duration::MAX, 1);
+    assert_eq!(once.unwrap_err(), SendError::DelayTooLong(1));
+    let every = probe.sender().send_every(Duration::MAX, 2);
+    assert_eq!(every.unwrap_err(), SendError::DelayTooLong(2));
+    assert_eq!(probe.take(), vec![]);
+}
+
+#[test]
+#[should_panic(expected = "timer period must be greater than zero")]
+fn zero_timer_period_panics() {
+    let probe = Probe::new();
+    let _ = probe.sender().send_every(Duration::from_secs(0), 0);
+}
+
+struct FlowHandler {
+    data: Vec<i32>,
+    flows: Vec<Flow>,
+    sender: Option<Sender<i32>>,
+}
+
+impl Handler<i32> for FlowHandler {
+    type Output = Vec<i32>;
+    fn start(&mut self, sender: Sender<i32>) {
+        for i in 0..self.flows.len() as i32 {
+            sender.send(i).unwrap();
+        }
+        // Keeps the loop alive so that only the returned `Flow` can end it.
+        self.sender = Some(sender);
+    }
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.data.push(i);
+        self.flows[i as usize]
+    }
+    fn end(self) -> Vec<i32> {
+        self.data
+    }
+}
+
+#[test]
+fn flow_stop_drops_queued_events() {
+    let result = run(FlowHandler {
+        data: vec![],
+        flows: vec![Flow::Continue, Flow::Stop, Flow::Continue],
+        sender: None,
+    });
+    assert_eq!(result, (Exit::Stopped, vec![0, 1]));
+}
+
+#[test]
+fn flow_drain_handles_queued_events() {
+    let result = run(FlowHandler {
+        data: vec![],
+        flows: vec![Flow::Drain, Flow::Continue, Flow::Continue],
+        sender: None,
+    });
+    assert_eq!(result, (Exit::Stopped, vec![0, 1, 2]));
+}
+
+#[test]
+fn flow_exit_reports_code() {
+    let result = run(FlowHandler {
+        data: vec![],
+        flows: vec![Flow::Drain, Flow::Exit(7), Flow::Continue],
+        sender: None,
+    });
+    assert_eq!(result, (Exit::Code(7), vec![0, 1]));
+}
+
+#[test]
+fn spawn_named_thread() {
+    struct ThreadName;
+    impl Handler<()> for ThreadName {
+        type Output = Option<String>;
+        fn start(&mut self, _: Sender<()>) {}
+        fn handle(&mut self, _: (), _: &mut Context<()>) -> Flow {
+            Flow::Stop
+        }
+        fn end(self) -> Option<String> {
+            std::thread::current().name().map(String::from)
+        }
+    }
+    let looper = Builder::new()
+        .name("looper".to_string())
+        .spawn(ThreadName)
+        .unwrap();
+    assert_eq!(looper.thread().name(), Some("looper"));
+    looper.sender().send(()).unwrap();
+    assert_eq!(
+        looper.join().unwrap(),
+        (Exit::Stopped, Some("looper".to_string()))
+    );
+}
+
+#[derive(Default)]
+struct Collect(Vec<i32>);
+
+impl Handler<i32> for Collect {
+    type Output = Vec<i32>;
+    fn start(&mut self, _: Sender<i32>) {}
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.0.push(i);
+        Flow::Continue
+    }
+    fn end(self) -> Vec<i32> {
+        self.0
+    }
+}
+
+#[test]
+fn spawn_join_returns_output() {
+    let looper = spawn(Collect::default());
+    for i in 1..4 {
+        looper.sender().send(i).unwrap();
+    }
+    assert_eq!(looper.join().unwrap(), (Exit::Disconnected, vec![1, 2, 3]));
+}
+
+struct Burst {
+    events: Vec<i32>,
+    rejected: Vec<SendError<i32>>,
+    data: Vec<i32>,
+}
+
+impl Burst {
+    fn new(events: Vec<i32>) -> Burst {
+        Burst {
+            events,
+            rejected: vec![],
+            data: vec![],
+        }
+    }
+}
+
+impl Handler<i32> for Burst {
+    type Output = (Vec<i32>, Vec<SendError<i32>>);
+    fn start(&mut self, sender: Sender<i32>) {
+        for &i in &self.events {
+            if let Err(error) = sender.send(i) {
+                self.rejected.push(error);
+            }
+        }
+    }
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.data.push(i);
+        Flow::Continue
+    }
+    fn end(self) -> (Vec<i32>, Vec<SendError<i32>>) {
+        (self.data, self.rejected)
+    }
+}
+
+fn run_bounded(overflow: Overflow) -> (Vec<i32>, Vec<SendError<i32>>) {
+    Builder::new()
+        .capacity(2)
+        .overflow(overflow)
+        .run(Burst::new(vec![1, 2, 3, 4]))
+        .1
+}
+
+#[test]
+fn overflow_fail() {
+    assert_eq!(
+        run_bounded(Overflow::Fail),
+        (vec![1, 2], vec![SendError::Full(3), SendError::Full(4)])
+    );
+}
+
+#[test]
+fn overflow_drop_newest() {
+    assert_eq!(run_bounded(Overflow::DropNewest), (vec![1, 2], vec![]));
+}
+
+#[test]
+fn overflow_drop_oldest() {
+    assert_eq!(run_bounded(Overflow::DropOldest), (vec![3, 4], vec![]));
+}
+
+#[test]
+fn overflow_block() {
+    let looper = Builder::new()
+        .capacity(1)
+        .overflow(Overflow::Block)
+        .spawn(Collect::default())
+        .unwrap();
+    for i in 0..100 {
+        looper.sender().send(i).unwrap();
+    }
+    let (_, data) = looper.join().unwrap();
+    assert_eq!(data, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn send_after_termination_fails() {
+    // Stops only on an event sent below, so that the send can't race with termination.
+    let looper = spawn(FnHandler::new((), |_, i: i32, _| Flow::from(i != 0)));
+    let sender = looper.sender().clone();
+    sender.send(0).unwrap();
+    looper.join().unwrap();
+    assert_eq!(sender.send(1), Err(SendError::Disconnected(1)));
+}
+
+#[test]
+fn priority_lanes() {
+    struct Prioritized(Vec<i32>);
+    impl Handler<i32> for Prioritized {
+        type Output = Vec<i32>;
+        fn start(&mut self, sender: Sender<i32>) {
+            let low = sender.with_priority(Priority::Low);
+            let high = sender.with_priority(Priority::High);
+            low.send(1).unwrap();
+            sender.send(2).unwrap();
+            high.send(3).unwrap();
+            low.send(4).unwrap();
+            sender.send(5).unwrap();
+            high.send(6).unwrap();
+        }
+        fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+            self.0.push(i);
+            Flow::Continue
+        }
+        fn end(self) -> Vec<i32> {
+            self.0
+        }
+    }
+    assert_eq!(run(Prioritized(vec![])).1, vec![3, 6, 2, 5, 1, 4]);
+}
+
+#[test]
+fn timers_fire_into_their_priority_lane() {
+    let handler = FnHandler::new(vec![], |data: &mut Vec<i32>, i, context| {
+        if i == 0 {
+            // The timer is due by now but must not skip ahead of this event.
+            let high = context.sender().with_priority(Priority::High);
+            high.send(2).unwrap();
+        }
+        data.push(i);
+        Flow::Continue
+    })
+    .on_start(|_, sender| {
+        let low = sender.with_priority(Priority::Low);
+        low.send_after(Duration::from_secs(0), 1).unwrap();
+        low.send(0).unwrap();
+    });
+    assert_eq!(Builder::new().batch(1).run(handler).1, vec![0, 2, 1]);
+}
+
+#[test]
+fn overflow_drop_oldest_prefers_low_priority() {
+    struct Mixed(Vec<i32>);
+    impl Handler<i32> for Mixed {
+        type Output = Vec<i32>;
+        fn start(&mut self, sender: Sender<i32>) {
+            let low = sender.with_priority(Priority::Low);
+            sender.send(1).unwrap();
+            low.send(2).unwrap();
+            sender.send(3).unwrap();
+        }
+        fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+            self.0.push(i);
+            Flow::Continue
+        }
+        fn end(self) -> Vec<i32> {
+            self.0
+        }
+    }
+    let (_, data) = Builder::new()
+        .capacity(2)
+        .overflow(Overflow::DropOldest)
+        .run(Mixed(vec![]));
+    assert_eq!(data, vec![1, 3]);
+}
+
+struct Idler {
+    idle: Vec<usize>,
+    handled: usize,
+    again: usize,
+}
+
+impl Handler<i32> for Idler {
+    type Output = Vec<usize>;
+    fn start(&mut self, sender: Sender<i32>) {
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        sender.send_after(Duration::from_millis(10), 3).unwrap();
+    }
+    fn handle(&mut self, _: i32, _: &mut Context<i32>) -> Flow {
+        self.handled += 1;
+        Flow::Continue
+    }
+    fn idle(&mut self) -> Idle {
+        self.idle.push(self.handled);
+        if self.again > 0 {
+            self.again -= 1;
+            Idle::Again
+        } else {
+            Idle::Wait
+        }
+    }
+    fn end(self) -> Vec<usize> {
+        self.idle
+    }
+}
+
+#[test]
+fn idle_after_queue_drains() {
+    let idler = Idler {
+        idle: vec![],
+        handled: 0,
+        again: 0,
+    };
+    assert_eq!(run(idler).1, vec![2, 3]);
+}
+
+#[test]
+fn idle_again() {
+    let idler = Idler {
+        idle: vec![],
+        handled: 0,
+        again: 2,
+    };
+    assert_eq!(run(idler).1, vec![2, 2, 2, 3]);
+}
+
+struct Batches(Vec<Vec<i32>>);
+
+impl Handler<i32> for Batches {
+    type Output = Vec<Vec<i32>>;
+    fn start(&mut self, sender: Sender<i32>) {
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+    }
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.0.push(vec![i]);
+        Flow::Continue
+    }
+    fn handle_batch(&mut self, batch: &mut Batch<i32>, _: &mut Context<i32>) -> Flow {
+        self.0.push(batch.collect());
+        Flow::Continue
+    }
+    fn end(self) -> Vec<Vec<i32>> {
+        self.0
+    }
+}
+
+#[test]
+fn batch_limited_by_builder() {
+    let (_, batches) = Builder::new().batch(2).run(Batches(vec![]));
+    assert_eq!(batches, vec![vec![0, 1], vec![2, 3], vec![4]]);
+}
+
+#[test]
+fn batch_defaults_to_single_event() {
+    let (_, batches) = run(Batches(vec![]));
+    assert_eq!(batches, vec![vec![0], vec![1], vec![2], vec![3], vec![4]]);
+}
+
+#[test]
+fn batch_default_forwards_to_handle() {
+    let (exit, data) = Builder::new().batch(10).run(FlowHandler {
+        data: vec![],
+        flows: vec![
+            Flow::Continue,
+            Flow::Drain,
+            Flow::Continue,
+            Flow::Stop,
+            Flow::Continue,
+        ],
+        sender: None,
+    });
+    assert_eq!((exit, data), (Exit::Stopped, vec![0, 1, 2, 3]));
+}
+
+struct Fragile(Vec<i32>);
+
+impl Handler<i32> for Fragile {
+    type Output = Vec<i32>;
+    fn start(&mut self, sender: Sender<i32>) {
+        for i in 0..4 {
+            sender.send(i).unwrap();
+        }
+    }
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        if i == 1 {
+            panic!("cannot handle {}", i);
+        }
+        self.0.push(i);
+        Flow::Continue
+    }
+    fn end(self) -> Vec<i32> {
+        self.0
+    }
+}
+
+#[test]
+fn panic_stops_and_calls_end() {
+    let (exit, data) = run(Fragile(vec![]));
+    match exit {
+        Exit::Panicked(panic) => assert_eq!(panic.message(), Some("cannot handle 1")),
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+    assert_eq!(data, vec![0]);
+}
+
+#[test]
+fn panic_skips_event() {
+    let (exit, data) = Builder::new()
+        .on_panic(PanicPolicy::Skip)
+        .run(Fragile(vec![]));
+    assert_eq!(exit, Exit::Disconnected);
+    assert_eq!(data, vec![0, 2, 3]);
+}
+
+#[test]
+fn panic_skips_event_in_batch() {
+    let (_, data) = Builder::new()
+        .batch(4)
+        .on_panic(PanicPolicy::Skip)
+        .run(Fragile(vec![]));
+    assert_eq!(data, vec![0, 2, 3]);
+}
+
+struct Picky(Vec<i32>);
+
+impl Handler<i32> for Picky {
+    type Output = Vec<i32>;
+    fn start(&mut self, _: Sender<i32>) {}
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        if i == 1 {
+            panic!("cannot handle {}", i);
+        }
+        self.0.push(i);
+        Flow::Continue
+    }
+    fn end(self) -> Vec<i32> {
+        self.0
+    }
+}
+
+#[test]
+fn supervisor_keeps_senders_across_restarts() {
+    let looper = spawn(Supervisor::new(|| Picky(vec![])));
+    for i in 0..4 {
+        looper.sender().send(i).unwrap();
+    }
+    let (exit, data) = looper.join().unwrap();
+    assert_eq!(exit, Exit::Disconnected);
+    assert_eq!(data, vec![2, 3]);
+}
+
+#[test]
+fn supervisor_gives_up() {
+    use std::sync::{Arc, Mutex};
+    let restarts = Arc::new(Mutex::new(vec![]));
+    let reported = restarts.clone();
+    let supervisor = Supervisor::new(|| Fragile(vec![]))
+        .max_restarts(2, Duration::from_secs(60))
+        .backoff(Duration::from_millis(1), Duration::from_millis(3))
+        .on_restart(move |restart| {
+            assert_eq!(restart.panic().message(), Some("cannot handle 1"));
+            reported
+                .lock()
+                .unwrap()
+                .push((restart.count(), restart.delay()));
+        });
+    let (exit, _) = run(supervisor);
+    assert_eq!(
+        exit,
+        Exit::Panicked(Panic::new(Box::new("cannot handle 1".to_string())))
+    );
+    assert_eq!(
+        *restarts.lock().unwrap(),
+        vec![(1, Duration::from_millis(1)), (2, Duration::from_millis(1))]
+    );
+}
+
+enum Request {
+    Add(i32, i32, Reply<i32>),
+    Stop,
+    Ignore(Reply<i32>),
+}
+
+struct Calculator;
+
+impl Handler<Request> for Calculator {
+    type Output = ();
+    fn start(&mut self, _: Sender<Request>) {}
+    fn handle(&mut self, request: Request, _: &mut Context<Request>) -> Flow {
+        match request {
+            Request::Add(a, b, reply) => reply.send(a + b),
+            Request::Stop => return Flow::Stop,
+            Request::Ignore(reply) => mem::forget(reply),
+        }
+        Flow::Continue
+    }
+    fn end(self) {}
+}
+
+#[test]
+fn call_returns_reply() {
+    let looper = spawn(Calculator);
+    let sum = looper.sender().call(|reply| Request::Add(2, 3, reply));
+    assert_eq!(sum, Ok(5));
+    let pending = looper.sender().ask(|reply| Request::Add(4, 5, reply));
+    assert_eq!(pending.unwrap().wait(), Ok(9));
+    looper.join().unwrap();
+}
+
+#[test]
+fn call_fails_when_loop_terminates_first() {
+    let looper = Builder::new().spawn(Calculator).unwrap();
+    let sender = looper.sender().clone();
+    sender.send(Request::Stop).unwrap();
+    let result = sender.call(|reply| Request::Add(1, 1, reply));
+    assert!(
+        result == Err(CallError::NoReply) || result == Err(CallError::Disconnected),
+        "{:?}",
+        result
+    );
+    drop(sender);
+    looper.join().unwrap();
+}
+
+#[test]
+fn call_times_out() {
+    let looper = spawn(Calculator);
+    let result = looper
+        .sender()
+        .call_timeout(Duration::from_millis(10), Request::Ignore);
+    assert_eq!(result, Err(CallError::Timeout));
+    looper.join().unwrap();
+}
+
+#[test]
+fn invoker_runs_jobs_between_events() {
+    let looper = spawn(Batches(vec![]));
+    let invoker = looper.invoker().clone();
+    invoker.invoke(|batches| batches.0.push(vec![-1])).unwrap();
+    looper.sender().send(5).unwrap();
+    let count = invoker.call(|batches| batches.0.len()).unwrap();
+    drop(invoker);
+    let (exit, batches) = looper.join().unwrap();
+    assert_eq!(exit, Exit::Disconnected);
+    assert_eq!(batches.len(), 7);
+    // Events sent by `Handler::start` may be queued after the job.
+    let job = batches.iter().position(|batch| *batch == [-1]).unwrap();
+    let event = batches.iter().position(|batch| *batch == [5]).unwrap();
+    assert!(job < event && event < count, "{:?}", batches);
+}
+
+struct SelfInvoking {
+    data: Vec<i32>,
+    invoker: Option<Invoker<i32, SelfInvoking>>,
+}
+
+impl Handler<i32> for SelfInvoking {
+    type Output = Vec<i32>;
+    fn start(&mut self, sender: Sender<i32>) {
+        sender.send(1).unwrap();
+    }
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.data.push(i);
+        // Dropping the invoker lets the event loop disconnect after the job.
+        if let Some(invoker) = self.invoker.take() {
+            invoker.invoke(|handler| handler.data.push(-1)).unwrap();
+        }
+        Flow::Continue
+    }
+    fn end(self) -> Vec<i32> {
+        self.data
+    }
+}
+
+#[test]
+fn run_with_passes_invoker_to_current_thread_loop() {
+    let handler = SelfInvoking {
+        data: vec![],
+        invoker: None,
+    };
+    let result = Builder::new().run_with(handler, |handler, invoker| {
+        handler.invoker = Some(invoker);
+    });
+    assert_eq!(result, (Exit::Disconnected, vec![1, -1]));
+}
+
+#[test]
+fn invoker_fails_after_loop_terminates() {
+    let looper = spawn(Calculator);
+    let invoker = looper.invoker().clone();
+    looper.sender().send(Request::Stop).unwrap();
+    looper.join().unwrap();
+    assert_eq!(invoker.call(|_| ()), Err(CallError::Disconnected));
+}
+
+struct Countdown {
+    seqs: Vec<u64>,
+    exit: bool,
+}
+
+impl Handler<u32> for Countdown {
+    type Output = Vec<u64>;
+    fn start(&mut self, sender: Sender<u32>) {
+        sender.send(3).unwrap();
+    }
+    fn handle(&mut self, i: u32, context: &mut Context<u32>) -> Flow {
+        self.seqs.push(context.seq());
+        if i > 0 {
+            context.sender().send(i - 1).unwrap();
+        } else if self.exit {
+            context.exit(5);
+        }
+        Flow::Continue
+    }
+    fn end(self) -> Vec<u64> {
+        self.seqs
+    }
+}
+
+#[test]
+fn context_sender_keeps_loop_alive() {
+    let countdown = Countdown {
+        seqs: vec![],
+        exit: false,
+    };
+    let (exit, seqs) = run(countdown);
+    assert_eq!(exit, Exit::Disconnected);
+    assert_eq!(seqs, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn context_exit() {
+    let countdown = Countdown {
+        seqs: vec![],
+        exit: true,
+    };
+    assert_eq!(run(countdown).0, Exit::Code(5));
+}
+
+struct Stopper(Vec<(u64, u64)>);
+
+impl Handler<i32> for Stopper {
+    type Output = Vec<(u64, u64)>;
+    fn start(&mut self, sender: Sender<i32>) {
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+    }
+    fn handle(&mut self, i: i32, context: &mut Context<i32>) -> Flow {
+        assert!(i != 1, "cannot handle 1");
+        self.0.push((context.seq(), context.stats().panics()));
+        if i == 3 {
+            context.stop();
+        }
+        Flow::Continue
+    }
+    fn end(self) -> Vec<(u64, u64)> {
+        self.0
+    }
+}
+
+#[test]
+fn context_stop_ends_batch() {
+    let (exit, seen) = Builder::new()
+        .batch(10)
+        .on_panic(PanicPolicy::Skip)
+        .run(Stopper(vec![]));
+    assert_eq!(exit, Exit::Stopped);
+    // The panic skips the rest of the first batch, so the second batch starts with event 2.
+    assert_eq!(seen, vec![(0, 0), (2, 1), (3, 1)]);
+}
+
+#[test]
+fn weak_sender_does_not_keep_loop_alive() {
+    let looper = spawn(Batches(vec![]));
+    let weak = looper.sender().with_priority(Priority::High).downgrade();
+    let sender = weak.upgrade().unwrap();
+    assert_eq!(sender.priority(), Priority::High);
+    sender.send(5).unwrap();
+    drop(sender);
+    let (exit, batches) = looper.join().unwrap();
+    assert_eq!(exit, Exit::Disconnected);
+    assert!(batches.contains(&vec![5]));
+    assert!(weak.upgrade().is_none());
+}
+
+struct Slow(Vec<i32>);
+
+impl Handler<i32> for Slow {
+    type Output = Vec<i32>;
+    fn start(&mut self, _: Sender<i32>) {}
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        thread::sleep(Duration::from_millis(10));
+        self.0.push(i);
+        Flow::Continue
+    }
+    fn end(self) -> Vec<i32> {
+        self.0
+    }
+}
+
+#[test]
+fn shutdown_drain() {
+    let looper = spawn(Slow(vec![]));
+    let sender = looper.sender().clone();
+    for i in 0..3 {
+        sender.send(i).unwrap();
+    }
+    sender.shutdown_handle().drain();
+    let (exit, data) = looper.join().unwrap();
+    assert_eq!(exit, Exit::Shutdown);
+    assert_eq!(data, vec![0, 1, 2]);
+}
+
+#[test]
+fn shutdown_stop_discards_events() {
+    let looper = spawn(Slow(vec![]));
+    let sender = looper.sender().clone();
+    for i in 0..50 {
+        sender.send(i).unwrap();
+    }
+    sender.shutdown_handle().stop();
+    let (exit, data) = looper.join().unwrap();
+    assert_eq!(exit, Exit::Shutdown);
+    assert!(data.len() < 50, "{:?}", data);
+    assert_eq!(sender.send(0), Err(SendError::Disconnected(0)));
+}
+
+#[test]
+fn shutdown_drain_timeout_discards_events() {
+    let (started, handling) = mpsc::channel();
+    let handler = FnHandler::new(vec![], move |data: &mut Vec<i32>, i, _| {
+        let _ = started.send(());
+        thread::sleep(Duration::from_millis(10));
+        data.push(i);
+        Flow::Continue
+    });
+    let looper = spawn(handler);
+    let sender = looper.sender().clone();
+    for i in 0..50 {
+        sender.send(i).unwrap();
+    }
+    // The event being handled when the request arrives is never interrupted.
+    handling.recv().unwrap();
+    sender
+        .shutdown_handle()
+        .drain_timeout(Duration::from_millis(50));
+    let (exit, data) = looper.join().unwrap();
+    assert_eq!(exit, Exit::Shutdown);
+    assert!(!data.is_empty() && data.len() < 50, "{:?}", data);
+}
+
+#[test]
+fn shutdown_interrupts_timers() {
+    let looper = spawn(Slow(vec![]));
+    let shutdown = looper.sender().shutdown_handle();
+    looper
+        .sender()
+        .send_after(Duration::from_secs(60), 1)
+        .unwrap();
+    let thread = thread::spawn(move || looper.join().unwrap());
+    thread::sleep(Duration::from_millis(10));
+    shutdown.stop();
+    assert_eq!(thread.join().unwrap(), (Exit::Shutdown, vec![]));
+}
+
+#[derive(Default)]
+struct Mux {
+    numbers: i32,
+    words: Vec<String>,
+}
+
+impl Receive<i32> for Mux {
+    fn receive(&mut self, i: i32, _: &mut Context<Envelope<Mux>>) -> Flow {
+        self.numbers += i;
+        Flow::Continue
+    }
+}
+
+impl Receive<String> for Mux {
+    fn receive(&mut self, word: String, _: &mut Context<Envelope<Mux>>) -> Flow {
+        self.words.push(word);
+        Flow::Continue
+    }
+}
+
+impl Handler<Envelope<Mux>> for Mux {
+    type Output = (i32, Vec<String>);
+    fn start(&mut self, _: Sender<Envelope<Mux>>) {}
+    fn handle(&mut self, envelope: Envelope<Mux>, context: &mut Context<Envelope<Mux>>) -> Flow {
+        envelope.open(self, context)
+    }
+    fn end(self) -> (i32, Vec<String>) {
+        (self.numbers, self.words)
+    }
+}
+
+#[test]
+fn typed_senders_dispatch_by_type() {
+    let looper = spawn(Mux::default());
+    let numbers = looper.sender().typed::<i32>();
+    let words = looper.sender().typed::<String>();
+    let producer = thread::spawn(move || {
+        for i in 1..4 {
+            numbers.send(i).unwrap();
+        }
+    });
+    words.send("hello".to_string()).unwrap();
+    producer.join().unwrap();
+    let shutdown = looper.sender().shutdown_handle();
+    shutdown.drain();
+    assert_eq!(
+        looper.join().unwrap(),
+        (Exit::Shutdown, (6, vec!["hello".to_string()]))
+    );
+    assert_eq!(
+        words.send("late".to_string()),
+        Err(SendError::Disconnected("late".to_string()))
+    );
+}
+
+#[test]
+fn mapped_sender_converts_events() {
+    let looper = spawn(Batches(vec![]));
+    let doubled = looper.sender().map(|i: i32| i * 2);
+    let clone = doubled.clone();
+    thread::spawn(move || clone.send(10).unwrap())
+        .join()
+        .unwrap();
+    let shutdown = looper.sender().shutdown_handle();
+    shutdown.drain();
+    let (_, batches) = looper.join().unwrap();
+    assert!(batches.contains(&vec![20]), "{:?}", batches);
+    assert_eq!(doubled.send(1), Err(SendError::Disconnected(1)));
+}
+
+#[test]
+fn mapped_sender_repeats_downgrades_and_shuts_down() {
+    let (ticked, ticks) = mpsc::channel();
+    let looper = spawn(FnHandler::new(ticked, |ticked, i: i32, _| {
+        let _ = ticked.send(i);
+        Flow::Continue
+    }));
+    let negated = looper.sender().map(|i: i32| -i);
+    let weak = negated.downgrade();
+    let timer = negated.send_every(Duration::from_millis(1), 3).unwrap();
+    for _ in 0..2 {
+        assert_eq!(ticks.recv().unwrap(), -3);
+    }
+    timer.cancel();
+    weak.upgrade().unwrap().send(4).unwrap();
+    while ticks.recv().unwrap() != -4 {}
+    negated.shutdown_handle().drain();
+    assert_eq!(looper.join().unwrap().0, Exit::Shutdown);
+    assert!(weak.upgrade().is_none());
+    let late = negated.send_after(Duration::from_millis(1), 5);
+    assert_eq!(late.unwrap_err(), SendError::Disconnected(5));
+}
+
+#[test]
+fn map_transforms_events() {
+    let (_, batches) = run(Map::new(Batches(vec![]), |i: i32| i * 10));
+    assert_eq!(
+        batches,
+        vec![vec![0], vec![10], vec![20], vec![30], vec![40]]
+    );
+}
+
+#[test]
+fn map_gives_inner_handler_its_own_event_type() {
+    let countdown = Countdown {
+        seqs: vec![],
+        exit: false,
+    };
+    // `Countdown` sends `u32` events, which reach the loop as text and are parsed back.
+    let map = Map::with_unmap(
+        countdown,
+        |text: String| text.parse::<u32>().unwrap(),
+        |i: u32| i.to_string(),
+    );
+    let (exit, seqs) = run(map);
+    assert_eq!(exit, Exit::Disconnected);
+    assert_eq!(seqs, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn split_routes_either_to_typed_handlers() {
+    let countdown = Countdown {
+        seqs: vec![],
+        exit: true,
+    };
+    let split = Split::new(countdown, Collect::default());
+    let (exit, (seqs, collected)) = Builder::new().run_with(split, |_, invoker| {
+        invoker.sender().send(Either::Right(7)).unwrap();
+    });
+    // `Countdown` sends through its own sender and context, and exits through its context.
+    assert_eq!(exit, Exit::Code(5));
+    assert_eq!(seqs, vec![1, 2, 3, 4]);
+    assert_eq!(collected, vec![7]);
+}
+
+#[test]
+fn chain_feeds_output_to_next_handler() {
+    let first = FlowHandler {
+        data: vec![],
+        flows: vec![Flow::Continue, Flow::Stop],
+        sender: None,
+    };
+    let chain = Chain::new(first, |data: Vec<i32>| Fragile(data));
+    let (exit, output) = Builder::new().on_panic(PanicPolicy::Skip).run(chain);
+    assert_eq!(exit, Exit::Disconnected);
+    // `Fragile` sends 0..4 when started and skips 1.
+    assert_eq!(output, Some(Either::Right(vec![0, 1, 0, 2, 3])));
+}
+
+#[test]
+fn chain_ends_first_handler_if_loop_terminates() {
+    let first = FlowHandler {
+        data: vec![],
+        flows: vec![Flow::Exit(3)],
+        sender: None,
+    };
+    let chain = Chain::new(first, Fragile);
+    assert_eq!(run(chain), (Exit::Code(3), Some(Either::Left(vec![0]))));
+}
+
+#[test]
+fn chain_ends_with_none_if_switch_panics() {
+    let first = FlowHandler {
+        data: vec![],
+        flows: vec![Flow::Stop],
+        sender: None,
+    };
+    let chain = Chain::new(first, |_: Vec<i32>| -> Fragile {
+        panic!("no second handler")
+    });
+    let (exit, output) = run(chain);
+    match exit {
+        Exit::Panicked(panic) => assert_eq!(panic.message(), Some("no second handler")),
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+    assert_eq!(output, None);
+}
+
+#[test]
+fn fn_handler_on_spawned_loop() {
+    let handler = FnHandler::new(0, |count: &mut usize, _: (), context| {
+        *count += 1;
+        if *count == 3 {
+            context.stop();
+        }
+        Flow::Continue
+    });
+    let looper = spawn(handler);
+    for _ in 0..5 {
+        let _ = looper.sender().send(());
+    }
+    assert_eq!(looper.join().unwrap(), (Exit::Stopped, 3));
+}
+
+#[test]
+fn fn_handler_runs_non_send_closures() {
+    let seen = Rc::new(RefCell::new(vec![]));
+    let log = Rc::clone(&seen);
+    let handler = FnHandler::new((), move |_, i: i32, _| {
+        log.borrow_mut().push(i);
+        Flow::Continue
+    })
+    .on_start(|_, sender| sender.send(1).unwrap())
+    .on_end({
+        let seen = Rc::clone(&seen);
+        move |_| seen.borrow().len()
+    });
+    assert_eq!(run(handler), (Exit::Disconnected, 1));
+    assert_eq!(*seen.borrow(), vec![1]);
+}
+
+struct LittleEndian;
+
+impl Codec<i32> for LittleEndian {
+    fn encode(&mut self, event: &i32, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(&event.to_le_bytes());
+        Ok(())
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<i32> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "expected 4 bytes"))?;
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    fn kind(&mut self, event: &i32) -> String {
+        if event % 2 == 0 { "even" } else { "odd" }.to_string()
+    }
+}
+
+fn journal_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("looper-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn collect() -> impl Handler<i32, Output = Vec<i32>> + Send {
+    FnHandler::new(vec![], |data: &mut Vec<i32>, i, _| {
+        data.push(i);
+        Flow::Continue
+    })
+}
+
+#[test]
+fn journal_rotates_segments_and_replays() {
+    let dir = journal_dir("journal-replay");
+    let looper = spawn(
+        Journal::new(collect(), LittleEndian, &dir)
+            .unwrap()
+            .segment_size(66),
+    );
+    for i in 0..5 {
+        looper.sender().send(i * 10).unwrap();
+    }
+    let (_, output) = looper.join().unwrap();
+    assert_eq!(output.unwrap(), vec![0, 10, 20, 30, 40]);
+    // The segment header takes 8 bytes and each record 29, so a segment fits two records.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+    let records: Vec<Record<i32>> = Replay::new(&dir, LittleEndian)
+        .unwrap()
+        .collect::<io::Result<_>>()
+        .unwrap();
+    let seqs: Vec<u64> = records.iter().map(|record| record.seq).collect();
+    assert_eq!(seqs, vec![0, 1, 2, 3, 4]);
+    assert!(records.iter().all(|record| record.kind == "even"));
+    assert!(records.windows(2).all(|pair| pair[0].time <= pair[1].time));
+
+    let replay = Replay::new(&dir, LittleEndian).unwrap();
+    assert_eq!(replay.run(collect()).unwrap(), vec![0, 10, 20, 30, 40]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reopened_journal_continues_numbering() {
+    let dir = journal_dir("journal-reopen");
+    for i in 0..3 {
+        let looper = spawn(Journal::new(collect(), LittleEndian, &dir).unwrap());
+        looper.sender().send(i).unwrap();
+        looper.sender().send(i).unwrap();
+        looper.join().unwrap().1.unwrap();
+    }
+    let seqs: Vec<u64> = Replay::<i32, _>::new(&dir, LittleEndian)
+        .unwrap()
+        .map(|record| record.unwrap().seq)
+        .collect();
+    assert_eq!(seqs, vec![0, 1, 2, 3, 4, 5]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replay_ignores_truncated_last_record() {
+    let dir = journal_dir("journal-truncated");
+    let looper = spawn(Journal::new(collect(), LittleEndian, &dir).unwrap());
+    looper.sender().send(1).unwrap();
+    looper.sender().send(2).unwrap();
+    looper.join().unwrap().1.unwrap();
+    let segment = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    let len = fs::metadata(&segment).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&segment)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let replay = Replay::new(&dir, LittleEndian).unwrap();
+    assert_eq!(replay.run(collect()).unwrap(), vec![1]);
+
+    // Reopening the journal removes the rest of the record before new segments follow.
+    let looper = spawn(Journal::new(collect(), LittleEndian, &dir).unwrap());
+    looper.sender().send(3).unwrap();
+    looper.join().unwrap().1.unwrap();
+    let records: Vec<(u64, i32)> = Replay::new(&dir, LittleEndian)
+        .unwrap()
+        .map(|record| record.map(|record| (record.seq, record.event)))
+        .collect::<io::Result<_>>()
+        .unwrap();
+    assert_eq!(records, vec![(0, 1), (1, 3)]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replay_rejects_malformed_segments() {
+    let dir = journal_dir("journal-malformed");
+    fs::create_dir_all(&dir).unwrap();
+    let first = dir.join("00000000000000000000.journal");
+    let replay_error = || {
+        Replay::<i32, _>::new(&dir, LittleEndian)
+            .unwrap()
+            .find_map(Result::err)
+            .unwrap()
+            .kind()
+    };
+    fs::write(&first, b"LOOPERJ\x09").unwrap();
+    assert_eq!(replay_error(), io::ErrorKind::InvalidData);
+    fs::write(&first, b"JOURNAL\x01").unwrap();
+    assert_eq!(replay_error(), io::ErrorKind::InvalidData);
+
+    // A record which claims to be longer than the rest of a segment followed by another one.
+    let mut segment = b"LOOPERJ\x01".to_vec();
+    segment.extend_from_slice(&0u64.to_le_bytes());
+    segment.extend_from_slice(&0u64.to_le_bytes());
+    segment.push(0);
+    segment.extend_from_slice(&u32::MAX.to_le_bytes());
+    segment.extend_from_slice(&7i32.to_le_bytes());
+    fs::write(&first, &segment).unwrap();
+    fs::write(dir.join("00000000000000000001.journal"), b"LOOPERJ\x01").unwrap();
+    assert_eq!(replay_error(), io::ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+struct Total(i32);
+
+impl Handler<i32> for Total {
+    type Output = i32;
+
+    fn start(&mut self, _: Sender<i32>) {}
+
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        self.0 += i;
+        Flow::Continue
+    }
+
+    fn end(self) -> i32 {
+        self.0
+    }
+}
+
+impl Codec<Total> for LittleEndian {
+    fn encode(&mut self, total: &Total, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.encode(&total.0, buf)
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<Total> {
+        Ok(Total(self.decode(bytes)?))
+    }
+}
+
+#[test]
+fn sourced_recovers_from_snapshot_and_journal() {
+    let dir = journal_dir("sourced");
+    let mut sourced = Sourced::open(&dir, LittleEndian, LittleEndian, || Total(0))
+        .unwrap()
+        .snapshot_every(2);
+    let (sender, _rx) = mailbox::channel(None, Overflow::default());
+    let mut context = Context::new(&sender);
+    for i in 1..6 {
+        assert_eq!(sourced.handle(i, &mut context), Flow::Continue);
+    }
+    // Crash without calling `end`.
+    drop(sourced);
+    let snapshots: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_str().unwrap().ends_with(".snapshot"))
+        .collect();
+    assert_eq!(snapshots, vec!["00000000000000000004.snapshot"]);
+
+    // The snapshot holds 1 + 2 + 3 + 4 and 5 is replayed from the journal.
+    let sourced = Sourced::open(&dir, LittleEndian, LittleEndian, || -> Total {
+        unreachable!()
+    })
+    .unwrap();
+    let looper = spawn(sourced);
+    looper.sender().send(100).unwrap();
+    assert_eq!(looper.join().unwrap().1.unwrap(), 115);
+    let sourced = Sourced::open(&dir, LittleEndian, LittleEndian, || -> Total {
+        unreachable!()
+    })
+    .unwrap();
+    assert_eq!(run(sourced).1.unwrap(), 115);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sourced_numbers_events_across_runs_and_compacts_journal() {
+    let dir = journal_dir("sourced-compact");
+    for (i, total) in [(1, 2), (2, 6), (3, 12)] {
+        let sourced = Sourced::open(&dir, LittleEndian, LittleEndian, || Total(0))
+            .unwrap()
+            .segment_size(1)
+            .snapshot_every(2);
+        let looper = spawn(sourced);
+        looper.sender().send(i).unwrap();
+        looper.sender().send(i).unwrap();
+        assert_eq!(looper.join().unwrap().1.unwrap(), total);
+    }
+    // Every event has its own segment. Only the last one is kept after the snapshot at 6.
+    let seqs: Vec<u64> = Replay::<i32, _>::new(&dir, LittleEndian)
+        .unwrap()
+        .map(|record| record.unwrap().seq)
+        .collect();
+    assert_eq!(seqs, vec![5]);
+    let sourced = Sourced::open(&dir, LittleEndian, LittleEndian, || -> Total {
+        unreachable!()
+    })
+    .unwrap();
+    assert_eq!(run(sourced).1.unwrap(), 12);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Like `Total`, but panics on 13.
+struct Unlucky(i32);
+
+impl Handler<i32> for Unlucky {
+    type Output = i32;
+
+    fn start(&mut self, _: Sender<i32>) {}
+
+    fn handle(&mut self, i: i32, _: &mut Context<i32>) -> Flow {
+        assert_ne!(i, 13, "unlucky event");
+        self.0 += i;
+        Flow::Continue
+    }
+
+    fn end(self) -> i32 {
+        self.0
+    }
+}
+
+impl Codec<Unlucky> for LittleEndian {
+    fn encode(&mut self, unlucky: &Unlucky, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.encode(&unlucky.0, buf)
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<Unlucky> {
+        Ok(Unlucky(self.decode(bytes)?))
+    }
+}
+
+#[test]
+fn sourced_skips_panicking_events_and_partial_snapshots() {
+    let dir = journal_dir("sourced-unlucky");
+    // The process died before taking a snapshot, in the middle of writing one.
+    let looper = spawn(Journal::new(collect(), LittleEndian, &dir).unwrap());
+    for i in [1, 13, 2] {
+        looper.sender().send(i).unwrap();
+    }
+    looper.join().unwrap().1.unwrap();
+    let partial = dir.join("00000000000000000003.tmp");
+    fs::write(&partial, b"partial").unwrap();
+
+    let sourced = Sourced::open(&dir, LittleEndian, LittleEndian, || Unlucky(0)).unwrap();
+    assert!(!partial.exists());
+    assert_eq!(run(sourced).1.unwrap(), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn durable_sender_redelivers_unhandled_events() {
+    let dir = journal_dir("durable");
+    let handler = FnHandler::new(vec![], |data: &mut Vec<i32>, i, _| {
+        data.push(i);
+        if i == 2 {
+            Flow::Stop
+        } else {
+            Flow::Continue
+        }
+    });
+    let looper = spawn(handler);
+    let sender = DurableSender::open(&dir, LittleEndian, looper.sender().clone()).unwrap();
+    // Keep the event loop busy until all events are queued.
+    let (unblock, blocked) = mpsc::channel::<()>();
+    let _ = looper.invoker().invoke(move |_| blocked.recv().unwrap());
+    for i in 1..4 {
+        sender.send(i).unwrap();
+    }
+    unblock.send(()).unwrap();
+    assert_eq!(looper.join().unwrap(), (Exit::Stopped, vec![1, 2]));
+    drop(sender);
+
+    let looper = spawn(collect());
+    let sender = DurableSender::open(&dir, LittleEndian, looper.sender().clone()).unwrap();
+    sender.send(4).unwrap();
+    drop(sender);
+    assert_eq!(looper.join().unwrap().1, vec![3, 4]);
+
+    let looper = spawn(collect());
+    drop(DurableSender::open(&dir, LittleEndian, looper.sender().clone()).unwrap());
+    assert_eq!(looper.join().unwrap().1, vec![]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn durable_sender_locks_queue_and_acks_discarded_events() {
+    let dir = journal_dir("durable-lossy");
+    for (overflow, handled) in [(Overflow::DropNewest, 1), (Overflow::DropOldest, 3)] {
+        let looper = Builder::new()
+            .capacity(1)
+            .overflow(overflow)
+            .spawn(collect())
+            .unwrap();
+        let sender = DurableSender::open(&dir, LittleEndian, looper.sender().clone()).unwrap();
+        let error = DurableSender::open(&dir, LittleEndian, looper.sender().clone()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let _ = looper.invoker().invoke(move |_| blocked.recv().unwrap());
+        for i in 1..4 {
+            sender.send(i).unwrap();
+        }
+        unblock.send(()).unwrap();
+        drop(sender);
+        assert_eq!(looper.join().unwrap().1, vec![handled]);
+    }
+    // The discarded events were acknowledged, so they aren't sent again.
+    let looper = spawn(collect());
+    drop(DurableSender::open(&dir, LittleEndian, looper.sender().clone()).unwrap());
+    assert_eq!(looper.join().unwrap().1, vec![]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Forwards the latest of the values received in quick succession. Zero flushes it.
+struct Debouncer {
+    out: Sender<i32>,
+    timer: Option<Timer>,
+    latest: Option<i32>,
+}
+
+impl Handler<i32> for Debouncer {
+    type Output = Option<i32>;
+    fn start(&mut self, _: Sender<i32>) {}
+    fn handle(&mut self, i: i32, context: &mut Context<i32>) -> Flow {
+        if let Some(timer) = self.timer.take() {
+            timer.cancel();
+        }
+        if i == 0 {
+            if let Some(latest) = self.latest.take() {
+                self.out.send(latest).unwrap();
+            }
+        } else {
+            self.latest = Some(i);
+            let timer = context.sender().send_after(Duration::from_secs(1), 0);
+            self.timer = Some(timer.unwrap());
+        }
+        Flow::Continue
+    }
+    fn end(self) -> Option<i32> {
+        self.latest
+    }
+}
+
+#[test]
+fn harness_fires_timers_from_virtual_clock() {
+    let probe = Probe::new();
+    let mut harness = Harness::new(Debouncer {
+        out: probe.sender().clone(),
+        timer: None,
+        latest: None,
+    });
+    harness.send(1);
+    harness.send(2);
+    assert_eq!(harness.step(), Some(Flow::Continue));
+    assert_eq!(harness.handler().latest, Some(1));
+    assert_eq!(harness.advance(Duration::from_millis(999)), Flow::Continue);
+    assert_eq!(harness.handler().latest, Some(2));
+    assert_eq!(probe.take(), vec![]);
+    harness.send(3);
+    harness.advance(Duration::from_millis(999));
+    assert_eq!(probe.take(), vec![]);
+    harness.advance(Duration::from_millis(1));
+    assert_eq!(probe.take(), vec![3]);
+    assert_eq!(harness.elapsed(), Duration::from_millis(1999));
+    assert_eq!(harness.context().stats().events(), 4);
+
+    harness.send(4);
+    assert_eq!(harness.step(), Some(Flow::Continue));
+    assert_eq!(harness.step(), None);
+    harness.sender().shutdown_handle().drain();
+    assert_eq!(harness.run_pending(), Flow::Drain);
+    assert_eq!(harness.finish(), Some(4));
+}
+
+#[test]
+fn harness_repeats_periodic_timers() {
+    let handler = FnHandler::new(vec![], |ticks: &mut Vec<i32>, i, _| {
+        ticks.push(i);
+        Flow::from(ticks.len() < 3)
+    })
+    .on_start(|_, sender| {
+        sender.send_every(Duration::from_secs(10), 7).unwrap();
+    });
+    let mut harness = Harness::new(handler);
+    assert_eq!(harness.advance(Duration::from_secs(25)), Flow::Continue);
+    assert_eq!(harness.context().stats().events(), 2);
+    assert_eq!(harness.advance(Duration::from_secs(25)), Flow::Stop);
+    assert_eq!(harness.elapsed(), Duration::from_secs(30));
+    assert_eq!(harness.finish(), vec![7, 7, 7]);
+}
+
+/// Leaves the batch untouched the first time it's called.
+struct Hesitant {
+    handled: Vec<(i32, u64)>,
+    hesitated: bool,
+}
+
+impl Handler<i32> for Hesitant {
+    type Output = ();
+    fn start(&mut self, _: Sender<i32>) {}
+    fn handle(&mut self, i: i32, context: &mut Context<i32>) -> Flow {
+        self.handled.push((i, context.seq()));
+        Flow::Continue
+    }
+    fn handle_batch(&mut self, batch: &mut Batch<i32>, context: &mut Context<i32>) -> Flow {
+        if !self.hesitated {
+            self.hesitated = true;
+            return Flow::Continue;
+        }
+        match batch.next() {
+            Some(i) => self.handle(i, context),
+            None => Flow::Continue,
+        }
+    }
+    fn end(self) {}
+}
+
+#[test]
+fn harness_keeps_events_left_in_batch() {
+    let mut harness = Harness::new(Hesitant {
+        handled: vec![],
+        hesitated: false,
+    });
+    harness.send(1);
+    harness.send(2);
+    assert_eq!(harness.step(), Some(Flow::Continue));
+    assert_eq!(harness.handler().handled, vec![]);
+    assert_eq!(harness.run_pending(), Flow::Continue);
+    assert_eq!(harness.handler().handled, vec![(1, 0), (2, 1)]);
+    assert_eq!(harness.context().stats().events(), 2);
+    assert_eq!(harness.context().stats().batches(), 3);
+}
+
+#[test]
+#[should_panic(expected = "timer period must be greater than zero")]
+fn harness_rejects_zero_timer_period() {
+    let harness = Harness::new(collect());
+    let _ = harness.sender().send_every(Duration::from_secs(0), 1);
+}
@@ -0,0 +1,276 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::mailbox::Priority;
+
+/// Handle to a scheduled event, returned by `Sender::send_after` and `Sender::send_every`.
+///
+/// Dropping the handle does not cancel the timer.
+#[derive(Clone, Debug)]
+pub struct Timer {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Timer {
+    fn new() -> Timer {
+        Timer {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Prevents any further deliveries of the scheduled event.
+    ///
+    /// Can be called from any thread. Cancelling an already fired one-shot timer has no effect.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Returns `true` if `cancel` was called on this timer or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(AtomicOrdering::SeqCst)
+    }
+}
+
+/// Event waiting in the timer queue of the event loop.
+pub(crate) struct Scheduled<EVENT> {
+    deadline: Instant,
+    delay: Duration,
+    seq: u64,
+    // Priority of the sender which scheduled the event, used when it fires.
+    priority: Priority,
+    period: Option<Duration>,
+    source: Source<EVENT>,
+    timer: Timer,
+}
+
+/// Produces the event of a timer each time it fires.
+enum Source<EVENT> {
+    Once(EVENT),
+    Every(EVENT, fn(&EVENT) -> EVENT),
+    // Repeating timer scheduled through a sender created by `Sender::map`.
+    Mapped(Box<dyn FnMut() -> EVENT + Send>),
+}
+
+impl<EVENT> Source<EVENT> {
+    fn next(&mut self) -> EVENT {
+        match *self {
+            Source::Once(_) => unreachable!("one-shot timers fire only once"),
+            Source::Every(ref event, clone) => clone(event),
+            Source::Mapped(ref mut next) => next(),
+        }
+    }
+
+    fn into_event(self) -> EVENT {
+        match self {
+            Source::Once(event) | Source::Every(event, _) => event,
+            Source::Mapped(mut next) => next(),
+        }
+    }
+}
+
+impl<EVENT> Scheduled<EVENT> {
+    /// Creates a one-shot entry together with the handle that cancels it.
+    ///
+    /// Returns the event if the deadline can't be represented by `Instant`.
+    pub(crate) fn once(
+        delay: Duration,
+        priority: Priority,
+        event: EVENT,
+    ) -> Result<(Scheduled<EVENT>, Timer), EVENT> {
+        match Instant::now().checked_add(delay) {
+            Some(deadline) => Ok(Scheduled::new(
+                deadline,
+                delay,
+                None,
+                priority,
+                Source::Once(event),
+            )),
+            None => Err(event),
+        }
+    }
+
+    /// Creates a repeating entry together with the handle that cancels it.
+    ///
+    /// Returns the event if the first deadline can't be represented by `Instant`.
+    pub(crate) fn every(
+        period: Duration,
+        priority: Priority,
+        event: EVENT,
+    ) -> Result<(Scheduled<EVENT>, Timer), EVENT>
+    where
+        EVENT: Clone,
+    {
+        let deadline = match Instant::now().checked_add(period) {
+            Some(deadline) => deadline,
+            None => return Err(event),
+        };
+        let source = Source::Every(event, EVENT::clone);
+        Ok(Scheduled::new(
+            deadline,
+            period,
+            Some(period),
+            priority,
+            source,
+        ))
+    }
+
+    fn new(
+        deadline: Instant,
+        delay: Duration,
+        period: Option<Duration>,
+        priority: Priority,
+        source: Source<EVENT>,
+    ) -> (Scheduled<EVENT>, Timer) {
+        let timer = Timer::new();
+        let scheduled = Scheduled {
+            deadline,
+            delay,
+            seq: 0,
+            priority,
+            period,
+            source,
+            timer: timer.clone(),
+        };
+        (scheduled, timer)
+    }
+
+    /// Moves the first deadline to `delay` after `now`, as if the event was scheduled at `now`.
+    ///
+    /// Returns `false` if the new deadline can't be represented by `Instant`, in which case the
+    /// timer would never fire.
+    pub(crate) fn rebase(&mut self, now: Instant) -> bool {
+        match now.checked_add(self.delay) {
+            Some(deadline) => {
+                self.deadline = deadline;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn into_event(self) -> EVENT {
+        self.source.into_event()
+    }
+
+    /// Converts the events of this timer with `map` each time it fires.
+    pub(crate) fn map<T, F>(self, map: &Arc<F>) -> Scheduled<T>
+    where
+        EVENT: Send + 'static,
+        F: Fn(EVENT) -> T + Send + Sync + 'static,
+    {
+        let source = match self.source {
+            Source::Once(event) => Source::Once(map(event)),
+            Source::Every(event, clone) => {
+                let map = map.clone();
+                Source::Mapped(Box::new(move || map(clone(&event))))
+            }
+            Source::Mapped(mut next) => {
+                let map = map.clone();
+                Source::Mapped(Box::new(move || map(next())))
+            }
+        };
+        Scheduled {
+            deadline: self.deadline,
+            delay: self.delay,
+            seq: self.seq,
+            priority: self.priority,
+            period: self.period,
+            source,
+            timer: self.timer,
+        }
+    }
+}
+
+impl<EVENT> PartialEq for Scheduled<EVENT> {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline && self.seq == other.seq
+    }
+}
+
+impl<EVENT> Eq for Scheduled<EVENT> {}
+
+impl<EVENT> PartialOrd for Scheduled<EVENT> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<EVENT> Ord for Scheduled<EVENT> {
+    // Reversed so that `BinaryHeap` pops the earliest deadline first. Timers with equal deadlines
+    // fire in the order in which they were inserted.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+/// Timers owned by a single event loop, ordered by deadline.
+pub(crate) struct Timers<EVENT> {
+    heap: BinaryHeap<Scheduled<EVENT>>,
+    seq: u64,
+}
+
+impl<EVENT> Timers<EVENT> {
+    pub(crate) fn new() -> Timers<EVENT> {
+        Timers {
+            heap: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, mut scheduled: Scheduled<EVENT>) {
+        scheduled.seq = self.seq;
+        self.seq += 1;
+        self.heap.push(scheduled);
+    }
+
+    /// Instant at which the earliest active timer fires.
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        self.prune();
+        self.heap.peek().map(|scheduled| scheduled.deadline)
+    }
+
+    /// Removes the earliest timer due at `now` and returns its event, with the priority of the
+    /// sender which scheduled it.
+    ///
+    /// Repeating timers are re-armed for their next period. A timer which fell a whole period or more
+    /// behind is re-armed for one period after `now`, so that it fires once instead of catching up on
+    /// every missed period. A timer whose next deadline can't be represented by `Instant` is dropped.
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Option<(EVENT, Priority)> {
+        self.prune();
+        match self.heap.peek() {
+            Some(scheduled) if scheduled.deadline <= now => {}
+            _ => return None,
+        }
+        let mut scheduled = self.heap.pop().unwrap();
+        match scheduled.period {
+            Some(period) => {
+                let event = scheduled.source.next();
+                let priority = scheduled.priority;
+                let next = match scheduled.deadline.checked_add(period) {
+                    Some(next) if next > now => Some(next),
+                    _ => now.checked_add(period),
+                };
+                if let Some(next) = next {
+                    scheduled.deadline = next;
+                    self.insert(scheduled);
+                }
+                Some((event, priority))
+            }
+            None => Some((scheduled.source.into_event(), scheduled.priority)),
+        }
+    }
+
+    // Drops cancelled timers from the front of the queue.
+    fn prune(&mut self) {
+        while self
+            .heap
+            .peek()
+            .is_some_and(|scheduled| scheduled.timer.is_cancelled())
+        {
+            self.heap.pop();
+        }
+    }
+}